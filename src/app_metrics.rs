@@ -1,42 +1,99 @@
 use metrics::{describe_counter, describe_gauge, describe_histogram};
 
-pub const METRIC_DEVICE_INFO: &str = "meshtastic_device_info";
-pub const METRIC_HOPS_AWAY: &str = "meshtastic_hops_away";
-pub const METRIC_TEMPERATURE: &str = "meshtastic_temperature";
-
-pub const METRIC_RSSI: &str = "meshtastic_rssi";
-pub const METRIC_SNR: &str = "meshtastic_snr";
-pub const METRIC_VOLTAGE: &str = "meshtastic_voltage";
-pub const METRIC_CURRENT: &str = "meshtastic_current";
-pub const METRIC_BATTERY: &str = "meshtastic_battery";
-pub const METRIC_HUMIDITY: &str = "meshtastic_humidity";
-pub const METRIC_BAROMETRIC_PRESSURE: &str = "meshtastic_barometric_pressure";
-pub const METRIC_RX_MSG_COUNT: &str = "meshtastic_received_message_count";
-pub const METRIC_LAST_HEARD_SECS: &str = "meshtastic_last_heard_seconds";
-pub const METRIC_CHAN_UTIL: &str = "meshtastic_channel_utilization";
-pub const METRIC_AIR_UTIL: &str = "meshtastic_air_utilization";
-pub const METRIC_UPTIME: &str = "meshtastic_device_uptime_seconds";
-
-pub const METRIC_POS_SATS_IN_VIEW: &str = "meshtastic_satellites_in_view";
-pub const METRIC_IAQ: &str = "meshtastic_indoor_air_quality";
-pub const METRIC_GAS_RESISTANCE: &str = "meshtastic_gas_resistance";
-
-pub const METRIC_PM10_STANDARD: &str = "meshtastic_air_pm10_standard";
-pub const METRIC_PM25_STANDARD: &str = "meshtastic_air_pm25_standard";
-pub const METRIC_PM100_STANDARD: &str = "meshtastic_air_pm100_standard";
-pub const METRIC_PM10_ENVIRONMENTAL: &str = "meshtastic_air_pm10_environmental";
-pub const METRIC_PM25_ENVIRONMENTAL: &str = "meshtastic_air_pm25_environmental";
-pub const METRIC_PM100_ENVIRONMENTAL: &str = "meshtastic_air_pm100_environmental";
-pub const METRIC_PARTICLES_03UM: &str = "meshtastic_air_particles_03um";
-pub const METRIC_PARTICLES_05UM: &str = "meshtastic_air_particles_05um";
-pub const METRIC_PARTICLES_10UM: &str = "meshtastic_air_particles_10um";
-pub const METRIC_PARTICLES_25UM: &str = "meshtastic_air_particles_25um";
-pub const METRIC_PARTICLES_50UM: &str = "meshtastic_air_particles_50um";
-pub const METRIC_PARTICLES_100UM: &str = "meshtastic_air_particles_100um";
+pub const METRIC_RADIO_STATE: &str = "radio_connection_state";
+pub const METRIC_RADIO_RECONNECTS: &str = "radio_reconnects_total";
+pub const METRIC_DEADMAN_EXPIRED: &str = "deadman_expired";
+pub const METRIC_SECS_SINCE_LAST_PACKET: &str = "seconds_since_last_packet";
+pub const METRIC_QUEUE_DEPTH: &str = "queue_depth";
+pub const METRIC_PACKETS_PROCESSED: &str = "packets_processed_total";
+pub const METRIC_DECODE_ERRORS: &str = "decode_errors_total";
+pub const METRIC_NODES_KNOWN: &str = "nodes_known";
+pub const METRIC_NODE_EXPIRED: &str = "node_expired_total";
+
+pub const METRIC_DEVICE_INFO: &str = "device_info";
+pub const METRIC_HOPS_AWAY: &str = "hops_away";
+pub const METRIC_HOPS_HISTOGRAM: &str = "packet_hops";
+pub const METRIC_TEMPERATURE: &str = "temperature";
+
+pub const METRIC_RSSI: &str = "rssi";
+pub const METRIC_SNR: &str = "snr";
+pub const METRIC_RSSI_HISTOGRAM: &str = "packet_rssi";
+pub const METRIC_SNR_HISTOGRAM: &str = "packet_snr";
+pub const METRIC_VOLTAGE: &str = "voltage";
+pub const METRIC_CURRENT: &str = "current";
+pub const METRIC_BATTERY: &str = "battery";
+pub const METRIC_HUMIDITY: &str = "humidity";
+pub const METRIC_BAROMETRIC_PRESSURE: &str = "barometric_pressure";
+pub const METRIC_RX_MSG_COUNT: &str = "received_message_count";
+pub const METRIC_LAST_HEARD_TIMESTAMP: &str = "last_heard_timestamp_seconds";
+pub const METRIC_LAST_HEARD_AGE: &str = "last_heard_age_seconds";
+pub const METRIC_CHAN_UTIL: &str = "channel_utilization";
+pub const METRIC_AIR_UTIL: &str = "air_utilization";
+pub const METRIC_UPTIME: &str = "device_uptime_seconds";
+
+pub const METRIC_TEXT_MESSAGES: &str = "text_messages_total";
+pub const METRIC_TEXT_MESSAGE_LENGTH: &str = "text_message_length_bytes";
+
+pub const METRIC_ROUTING_ACKS: &str = "routing_acks_total";
+pub const METRIC_ROUTING_ERRORS: &str = "routing_errors_total";
+
+pub const METRIC_TRACEROUTE_REQUESTS: &str = "traceroute_requests_total";
+pub const METRIC_TRACEROUTE_FAILURES: &str = "traceroute_failures_total";
+pub const METRIC_TRACEROUTE_ROUTE_LENGTH: &str = "traceroute_route_length";
+pub const METRIC_TRACEROUTE_HOP_SNR: &str = "traceroute_hop_snr";
+pub const METRIC_TRACEROUTE_RTT: &str = "traceroute_rtt_seconds";
+
+pub const METRIC_POLL_REQUESTS: &str = "poll_requests_total";
+pub const METRIC_POLL_RESPONSES: &str = "poll_responses_total";
+pub const METRIC_POLL_TIMEOUTS: &str = "poll_timeouts_total";
+
+pub const METRIC_NEIGHBOR_SNR: &str = "neighbor_snr";
+pub const METRIC_NEIGHBOR_LAST_SEEN: &str = "neighbor_last_seen_seconds";
+pub const METRIC_NEIGHBOR_BROADCAST_INTERVAL: &str = "neighbor_broadcast_interval_seconds";
+
+pub const METRIC_POS_SATS_IN_VIEW: &str = "satellites_in_view";
+pub const METRIC_POS_GEOHASH_INFO: &str = "position_geohash_info";
+pub const METRIC_POS_LATITUDE: &str = "position_latitude_degrees";
+pub const METRIC_POS_LONGITUDE: &str = "position_longitude_degrees";
+pub const METRIC_POS_ALTITUDE: &str = "position_altitude_meters";
+pub const METRIC_POS_GROUND_SPEED: &str = "position_ground_speed_meters_per_second";
+pub const METRIC_POS_GROUND_TRACK: &str = "position_ground_track_degrees";
+pub const METRIC_POS_PDOP: &str = "position_pdop";
+pub const METRIC_POS_HDOP: &str = "position_hdop";
+pub const METRIC_POS_PRECISION_BITS: &str = "position_precision_bits";
+pub const METRIC_POS_FIX_QUALITY: &str = "position_fix_quality";
+pub const METRIC_POS_FIX_TYPE: &str = "position_fix_type";
+pub const METRIC_POS_TIMESTAMP: &str = "position_timestamp_seconds";
+pub const METRIC_IAQ: &str = "indoor_air_quality";
+pub const METRIC_GAS_RESISTANCE: &str = "gas_resistance";
+
+pub const METRIC_PM10_STANDARD: &str = "air_pm10_standard";
+pub const METRIC_PM25_STANDARD: &str = "air_pm25_standard";
+pub const METRIC_PM100_STANDARD: &str = "air_pm100_standard";
+pub const METRIC_PM10_ENVIRONMENTAL: &str = "air_pm10_environmental";
+pub const METRIC_PM25_ENVIRONMENTAL: &str = "air_pm25_environmental";
+pub const METRIC_PM100_ENVIRONMENTAL: &str = "air_pm100_environmental";
+pub const METRIC_PARTICLES_03UM: &str = "air_particles_03um";
+pub const METRIC_PARTICLES_05UM: &str = "air_particles_05um";
+pub const METRIC_PARTICLES_10UM: &str = "air_particles_10um";
+pub const METRIC_PARTICLES_25UM: &str = "air_particles_25um";
+pub const METRIC_PARTICLES_50UM: &str = "air_particles_50um";
+pub const METRIC_PARTICLES_100UM: &str = "air_particles_100um";
 
 pub fn register_metrics() {
+    describe_gauge!(METRIC_RADIO_STATE, "1 for the current state of the connection to the radio, 0 for the others");
+    describe_counter!(METRIC_RADIO_RECONNECTS, "The number of times we've tried to reconnect to the radio");
+    describe_gauge!(METRIC_SECS_SINCE_LAST_PACKET, "Seconds since the last packet was received from the radio");
+    describe_gauge!(METRIC_DEADMAN_EXPIRED, "Whether it has been longer than deadman_timeout since the last packet (1) or not (0)");
+    describe_gauge!(METRIC_QUEUE_DEPTH, "Number of messages waiting in the internal queues");
+    describe_counter!(METRIC_PACKETS_PROCESSED, "The number of FromRadio packets processed");
+    describe_counter!(METRIC_DECODE_ERRORS, "The number of packets whose payload couldn't be processed");
+    describe_gauge!(METRIC_NODES_KNOWN, "The number of nodes heard from within the node ttl");
+    describe_counter!(METRIC_NODE_EXPIRED, "The number of nodes whose series were removed after not being heard from");
+
     describe_gauge!(METRIC_DEVICE_INFO, "information about device");
     describe_gauge!(METRIC_HOPS_AWAY, "The reported number of hops away the client is.");
+    describe_histogram!(METRIC_HOPS_HISTOGRAM, "The number of hops consumed by packets from the node");
 
     describe_gauge!(METRIC_TEMPERATURE,"The reported temperature from telemetry module");
     describe_gauge!(METRIC_HUMIDITY, "The relative humidity from telemetry module");
@@ -48,16 +105,51 @@ pub fn register_metrics() {
 
     describe_gauge!(METRIC_SNR, "Signal to noise ratio for node");
     describe_gauge!(METRIC_RSSI, "RSSI for node");
+    describe_histogram!(METRIC_RSSI_HISTOGRAM, "RSSI of packets received directly from the node");
+    describe_histogram!(METRIC_SNR_HISTOGRAM, "Signal to noise ratio of packets received directly from the node");
 
     describe_counter!(METRIC_RX_MSG_COUNT, "The number of messages received from the node");
-    describe_gauge!(METRIC_LAST_HEARD_SECS, "The number of seconds last heard");
+    describe_gauge!(METRIC_LAST_HEARD_TIMESTAMP, "Unix time the node was last heard from");
+    describe_gauge!(METRIC_LAST_HEARD_AGE, "The number of seconds since the node was last heard from");
 
     describe_gauge!(METRIC_CHAN_UTIL,"The channel's utilization");
     describe_gauge!(METRIC_AIR_UTIL, "The amount of total utilization");
 
     describe_gauge!(METRIC_UPTIME, "The total seconds the device has been energized.");
 
+    describe_counter!(METRIC_TEXT_MESSAGES, "The number of text messages received from the node on the channel");
+    describe_histogram!(METRIC_TEXT_MESSAGE_LENGTH, "The length of text messages received on the channel");
+
+    describe_counter!(METRIC_ROUTING_ACKS, "The number of routing acknowledgements sent by the node");
+    describe_counter!(METRIC_ROUTING_ERRORS, "The number of routing errors reported by the node, by reason");
+
+    describe_counter!(METRIC_TRACEROUTE_REQUESTS, "The number of traceroutes sent to the node");
+    describe_counter!(METRIC_TRACEROUTE_FAILURES, "The number of traceroutes to the node that never completed, by reason");
+    describe_gauge!(METRIC_TRACEROUTE_ROUTE_LENGTH, "The number of intermediate hops on the last traceroute to the node");
+    describe_gauge!(METRIC_TRACEROUTE_HOP_SNR, "The SNR of each hop on the last traceroute to the node");
+    describe_gauge!(METRIC_TRACEROUTE_RTT, "The round trip time of the last traceroute to the node");
+
+    describe_counter!(METRIC_POLL_REQUESTS, "The number of telemetry or position requests sent to the node");
+    describe_counter!(METRIC_POLL_RESPONSES, "The number of telemetry or position requests the node answered");
+    describe_counter!(METRIC_POLL_TIMEOUTS, "The number of telemetry or position requests the node never answered");
+
+    describe_gauge!(METRIC_NEIGHBOR_SNR, "SNR at which the reporting node last heard its neighbor");
+    describe_gauge!(METRIC_NEIGHBOR_LAST_SEEN, "Unix time the neighbor link was last reported");
+    describe_gauge!(METRIC_NEIGHBOR_BROADCAST_INTERVAL, "How often the node broadcasts its neighbor info");
+
     describe_gauge!(METRIC_POS_SATS_IN_VIEW, "The number of satellites in view for this node.");
+    describe_gauge!(METRIC_POS_GEOHASH_INFO, "The node's current geohash, value is when it was last reported");
+    describe_gauge!(METRIC_POS_LATITUDE, "The node's reported latitude");
+    describe_gauge!(METRIC_POS_LONGITUDE, "The node's reported longitude");
+    describe_gauge!(METRIC_POS_ALTITUDE, "The node's reported altitude above mean sea level");
+    describe_gauge!(METRIC_POS_GROUND_SPEED, "The node's ground speed");
+    describe_gauge!(METRIC_POS_GROUND_TRACK, "The node's true north track");
+    describe_gauge!(METRIC_POS_PDOP, "Positional dilution of precision of the node's fix");
+    describe_gauge!(METRIC_POS_HDOP, "Horizontal dilution of precision of the node's fix");
+    describe_gauge!(METRIC_POS_PRECISION_BITS, "How many bits of the position the node shares, 32 being full precision");
+    describe_gauge!(METRIC_POS_FIX_QUALITY, "GPS fix quality as reported by the node (NMEA GGA)");
+    describe_gauge!(METRIC_POS_FIX_TYPE, "GPS fix type as reported by the node, 2 for 2D, 3 for 3D");
+    describe_gauge!(METRIC_POS_TIMESTAMP, "Unix time at which the node took its position fix");
     describe_gauge!(METRIC_IAQ, "relative scale of VOC content measured from 0-500");
     describe_gauge!(METRIC_GAS_RESISTANCE, "Gas resistance in MOhms");
 
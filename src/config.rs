@@ -0,0 +1,356 @@
+use std::ffi::OsString;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use serde::Serialize;
+use serde_yaml::{Mapping, Value};
+use crate::structs::{AppConfig, MqttConfig, SerialConfig};
+
+const ENV_PREFIX: &str = "MESHTASTIC_EXPORTER_";
+const DEFAULT_CONFIG_FILE: &str = "./config.yaml";
+// struct fields are snake_case, but the keys of these maps are names whose case matters
+const MAP_FIELDS: &[&str] = &["global_labels", "connection.channels"];
+
+// Each layer overrides the one before it: AppConfig::default(), the config file,
+// MESHTASTIC_EXPORTER_* environment variables, then command line flags.
+#[derive(Debug, Parser)]
+#[command(version, about = "Prometheus exporter for meshtastic nodes")]
+pub struct Cli {
+    /// Config file to read, ./config.yaml is used if it exists and this isn't given
+    #[arg(short, long, env = "CONFIG_FILE_PATH")]
+    pub config: Option<String>,
+    /// Print the effective configuration as yaml and exit
+    #[arg(long)]
+    pub print_config: bool,
+    #[arg(long)]
+    pub metrics_port: Option<u16>,
+    #[arg(long)]
+    pub meshtastic_addr: Option<SocketAddr>,
+    #[arg(long)]
+    pub namespace: Option<String>,
+    #[arg(long)]
+    pub geohash_precision: Option<usize>,
+    #[arg(long)]
+    pub node_ttl: Option<u64>,
+    #[arg(long)]
+    pub drain_timeout: Option<u64>,
+    #[arg(long)]
+    pub deadman_timeout: Option<u64>,
+    /// reconnect, exit or unhealthy
+    #[arg(long)]
+    pub deadman_action: Option<String>,
+    #[arg(long)]
+    pub heartbeat_interval: Option<u64>,
+    /// Set any field, nested ones with dots, e.g. --set traceroute.enabled=true
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    pub set: Vec<String>,
+}
+
+pub fn load(cli: &Cli) -> Result<AppConfig> {
+    load_from(cli, std::env::vars_os())
+}
+
+fn load_from<I: IntoIterator<Item = (OsString, OsString)>>(cli: &Cli, vars: I) -> Result<AppConfig> {
+    let mut config = serde_yaml::to_value(AppConfig::default())?;
+
+    let path = cli.config.clone().unwrap_or(DEFAULT_CONFIG_FILE.to_string());
+    if cli.config.is_some() || Path::new(&path).exists() {
+        let yaml = fs::read_to_string(&path).with_context(|| format!("Can't read config file {path}"))?;
+        let file: Value = serde_yaml::from_str(&yaml).with_context(|| format!("Can't parse config file {path}"))?;
+        merge(&mut config, file);
+    } else {
+        info!("No config file at {path}, using defaults.");
+    }
+
+    // (path, text as given) for each value read from the environment or --set
+    let mut overrides: Vec<(Vec<String>, String)> = vec![];
+    for (name, value) in vars {
+        // a variable that isn't unicode can't be one of ours, and would panic std::env::vars()
+        let (Ok(name), Ok(value)) = (name.into_string(), value.into_string()) else { continue };
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else { continue };
+        let path = env_path(&config, key);
+        set(&mut config, &path, parse_value(&value))
+            .with_context(|| format!("Can't apply environment variable {name}"))?;
+        overrides.push((path, value));
+    }
+
+    let flags = [
+        ("metrics_port", flag(&cli.metrics_port)?),
+        ("meshtastic_addr", flag(&cli.meshtastic_addr)?),
+        ("namespace", flag(&cli.namespace)?),
+        ("geohash_precision", flag(&cli.geohash_precision)?),
+        ("node_ttl", flag(&cli.node_ttl)?),
+        ("drain_timeout", flag(&cli.drain_timeout)?),
+        ("deadman_timeout", flag(&cli.deadman_timeout)?),
+        ("deadman_action", flag(&cli.deadman_action)?),
+        ("heartbeat_interval", flag(&cli.heartbeat_interval)?),
+    ];
+    for (key, value) in flags {
+        if let Some(value) = value {
+            set(&mut config, &[key.to_string()], value)?;
+        }
+    }
+    for assignment in cli.set.iter() {
+        let Some((key, value)) = assignment.split_once('=') else {
+            bail!("--set {assignment} isn't in the form KEY=VALUE");
+        };
+        let path: Vec<String> = key.split('.').map(|s| s.to_string()).collect();
+        set(&mut config, &path, parse_value(value)).with_context(|| format!("Can't apply --set {assignment}"))?;
+        overrides.push((path, value.to_string()));
+    }
+
+    // a string field given something that reads as a number or boolean, like PASSWORD=123456,
+    // gets the text back instead
+    while let Some(field) = failing_field(&config) {
+        let Some((path, text)) = overrides.iter().rev().find(|(path, _)| {
+            path.join(".") == field && get(&config, path).is_some_and(|v| !v.is_string())
+        }) else { break };
+        set(&mut config, path, Value::String(text.clone()))?;
+    }
+
+    serde_yaml::from_value(config).context("Couldn't deserialize AppConfig")
+}
+
+// the effective configuration as yaml, with the mqtt password masked
+pub fn redacted_yaml(config: &AppConfig) -> Result<String> {
+    let mut value = serde_yaml::to_value(config)?;
+    if let Some(password) = value.get_mut("connection").and_then(|c| c.get_mut("password")) {
+        if !password.is_null() {
+            *password = Value::String("<redacted>".to_string());
+        }
+    }
+    Ok(serde_yaml::to_string(&value)?)
+}
+
+// environment variables are usually upper case, so field names are lowered.  A map key keeps
+// its case, unless it matches one that's already there, so CHANNELS__LONGFAST finds LongFast
+fn env_path(config: &Value, key: &str) -> Vec<String> {
+    let mut path: Vec<String> = vec![];
+    for segment in key.split("__") {
+        let segment = match MAP_FIELDS.contains(&path.join(".").as_str()) {
+            true => get(config, &path)
+                .and_then(Value::as_mapping)
+                .and_then(|map| map.keys().filter_map(Value::as_str).find(|k| k.eq_ignore_ascii_case(segment)))
+                .unwrap_or(segment)
+                .to_string(),
+            false => segment.to_lowercase(),
+        };
+        path.push(segment);
+    }
+    path
+}
+
+// the dotted path of the field that stops AppConfig deserializing, if there is one
+fn failing_field(config: &Value) -> Option<String> {
+    let error = serde_path_to_error::deserialize::<_, AppConfig>(config.clone()).err()?;
+    let path = error.path().to_string();
+    if path != "connection" {
+        return Some(path);
+    }
+    // connection is an internally tagged enum, the path stops at it, so ask its variant
+    let connection = config.get("connection")?;
+    let field = match connection.get("type").and_then(Value::as_str) {
+        Some("mqtt") => serde_path_to_error::deserialize::<_, MqttConfig>(connection.clone()).err(),
+        Some("serial") => serde_path_to_error::deserialize::<_, SerialConfig>(connection.clone()).err(),
+        _ => None,
+    };
+    Some(match field {
+        Some(e) => format!("connection.{}", e.path()),
+        None => path,
+    })
+}
+
+fn get<'a>(config: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter().try_fold(config, |value, key| value.get(key.as_str()))
+}
+
+fn flag<T: Serialize>(value: &Option<T>) -> Result<Option<Value>> {
+    Ok(match value {
+        Some(v) => Some(serde_yaml::to_value(v)?),
+        None => None,
+    })
+}
+
+// values are read as yaml, so numbers, booleans and lists like [a, b] come through typed
+fn parse_value(value: &str) -> Value {
+    match serde_yaml::from_str(value) {
+        // a bare device id like !a1b2c3d4 reads as a yaml tag
+        Ok(Value::Tagged(_)) | Err(_) => Value::String(value.to_string()),
+        Ok(v) => v,
+    }
+}
+
+// mappings are merged key by key, anything else in `layer` replaces what's in `base`
+fn merge(base: &mut Value, layer: Value) {
+    match (base, layer) {
+        (Value::Mapping(base), Value::Mapping(layer)) => {
+            for (key, value) in layer {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => { base.insert(key, value); }
+                }
+            }
+        }
+        // an empty file, or a key with nothing under it, leaves the defaults alone
+        (_, Value::Null) => {}
+        (base, layer) => *base = layer,
+    }
+}
+
+fn set(config: &mut Value, path: &[String], value: Value) -> Result<()> {
+    let Some((key, rest)) = path.split_first() else {
+        *config = value;
+        return Ok(());
+    };
+    if config.is_null() {
+        *config = Value::Mapping(Mapping::new());
+    }
+    let Value::Mapping(mapping) = config else {
+        bail!("{key} is nested under a field that isn't a mapping");
+    };
+    let entry = mapping.entry(Value::String(key.clone())).or_insert(Value::Null);
+    set(entry, rest, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::ConnectionConfig;
+    // main's #[macro_use] of tokio would otherwise turn #[test] into tokio::test
+    use std::prelude::v1::test;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(OsString, OsString)> {
+        vars.iter().map(|(k, v)| (OsString::from(k), OsString::from(v))).collect()
+    }
+
+    fn path(path: &str) -> Vec<String> {
+        path.split('.').map(|s| s.to_string()).collect()
+    }
+
+    fn mqtt(config: &AppConfig) -> &MqttConfig {
+        match &config.connection {
+            Some(ConnectionConfig::Mqtt(mqtt)) => mqtt,
+            other => panic!("expected an mqtt connection, got {other:?}"),
+        }
+    }
+
+    // a config file that's written for the test and removed when it's dropped
+    struct ConfigFile(std::path::PathBuf);
+
+    impl ConfigFile {
+        fn new(name: &str, yaml: &str) -> ConfigFile {
+            let path = std::env::temp_dir().join(format!("meshtastic_exporter-{}-{name}.yaml", std::process::id()));
+            fs::write(&path, yaml).unwrap();
+            ConfigFile(path)
+        }
+
+        fn cli(&self, args: &[&str]) -> Cli {
+            let mut argv = vec!["meshtastic_exporter", "--config", self.0.to_str().unwrap()];
+            argv.extend(args);
+            Cli::parse_from(argv)
+        }
+    }
+
+    impl Drop for ConfigFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let file = ConfigFile::new("precedence", "metrics_port: 9001\nnode_ttl: 100\nnamespace: from_file\n");
+        let config = load_from(
+            &file.cli(&["--metrics-port", "9003"]),
+            vars(&[("MESHTASTIC_EXPORTER_METRICS_PORT", "9002"), ("MESHTASTIC_EXPORTER_NODE_TTL", "200")]),
+        ).unwrap();
+        assert_eq!(config.metrics_port, 9003);
+        assert_eq!(config.node_ttl, 200);
+        assert_eq!(config.namespace, "from_file");
+    }
+
+    #[test]
+    fn empty_file_leaves_the_defaults() {
+        let file = ConfigFile::new("empty", "");
+        let config = load_from(&file.cli(&[]), vars(&[])).unwrap();
+        let defaults = AppConfig::default();
+        assert_eq!(config.metrics_port, defaults.metrics_port);
+        assert_eq!(config.namespace, defaults.namespace);
+        assert_eq!(config.node_ttl, defaults.node_ttl);
+    }
+
+    #[test]
+    fn numeric_string_is_kept_as_text() {
+        let file = ConfigFile::new("numeric", "connection:\n  type: mqtt\n");
+        let config = load_from(
+            &file.cli(&[]),
+            vars(&[("MESHTASTIC_EXPORTER_CONNECTION__PASSWORD", "123456")]),
+        ).unwrap();
+        assert_eq!(mqtt(&config).password.as_deref(), Some("123456"));
+    }
+
+    #[test]
+    fn non_unicode_variables_are_skipped() {
+        use std::os::unix::ffi::OsStringExt;
+        let file = ConfigFile::new("non_unicode", "");
+        let mut env = vars(&[("MESHTASTIC_EXPORTER_NODE_TTL", "300")]);
+        env.push((OsString::from("MESHTASTIC_EXPORTER_NAMESPACE"), OsString::from_vec(vec![0x66, 0xff])));
+        let config = load_from(&file.cli(&[]), env).unwrap();
+        assert_eq!(config.node_ttl, 300);
+        assert_eq!(config.namespace, AppConfig::default().namespace);
+    }
+
+    #[test]
+    fn env_path_keeps_map_key_case() {
+        let config = serde_yaml::to_value(AppConfig::default()).unwrap();
+        assert_eq!(env_path(&config, "GLOBAL_LABELS__Site"), path("global_labels.Site"));
+        assert_eq!(env_path(&config, "TRACEROUTE__ENABLED"), path("traceroute.enabled"));
+
+        let mut config = config;
+        merge(&mut config, serde_yaml::from_str("connection:\n  type: mqtt\n  channels:\n    LongFast: AQ==\n").unwrap());
+        assert_eq!(env_path(&config, "CONNECTION__CHANNELS__LONGFAST"), path("connection.channels.LongFast"));
+        assert_eq!(env_path(&config, "CONNECTION__CHANNELS__MyChannel"), path("connection.channels.MyChannel"));
+    }
+
+    #[test]
+    fn merge_replaces_leaves_and_keeps_the_rest() {
+        let mut base: Value = serde_yaml::from_str("a: 1\nb:\n  c: 2\n  d: 3\n").unwrap();
+        merge(&mut base, serde_yaml::from_str("b:\n  c: 4\ne: 5\n").unwrap());
+        assert_eq!(base, serde_yaml::from_str::<Value>("a: 1\nb:\n  c: 4\n  d: 3\ne: 5\n").unwrap());
+        merge(&mut base, Value::Null);
+        assert_eq!(get(&base, &path("b.d")), Some(&Value::from(3)));
+    }
+
+    #[test]
+    fn failing_field_names_the_nested_field() {
+        let mut config = serde_yaml::to_value(AppConfig::default()).unwrap();
+        assert_eq!(failing_field(&config), None);
+        set(&mut config, &path("node_ttl"), Value::String("soon".to_string())).unwrap();
+        assert_eq!(failing_field(&config).as_deref(), Some("node_ttl"));
+
+        let mut config = serde_yaml::to_value(AppConfig::default()).unwrap();
+        set(&mut config, &path("connection.type"), Value::String("mqtt".to_string())).unwrap();
+        set(&mut config, &path("connection.password"), Value::from(123456)).unwrap();
+        assert_eq!(failing_field(&config).as_deref(), Some("connection.password"));
+    }
+
+    #[test]
+    fn parse_value_types_what_it_can() {
+        assert_eq!(parse_value("42"), Value::from(42));
+        assert_eq!(parse_value("true"), Value::Bool(true));
+        assert_eq!(parse_value("[a, b]"), serde_yaml::from_str::<Value>("[a, b]").unwrap());
+        assert_eq!(parse_value("!a1b2c3d4"), Value::String("!a1b2c3d4".to_string()));
+        assert_eq!(parse_value("a: [b"), Value::String("a: [b".to_string()));
+    }
+
+    #[test]
+    fn redacted_yaml_masks_the_password() {
+        let file = ConfigFile::new("redacted", "connection:\n  type: mqtt\n  password: hunter2\n");
+        let config = load_from(&file.cli(&[]), vars(&[])).unwrap();
+        let yaml = redacted_yaml(&config).unwrap();
+        assert!(!yaml.contains("hunter2"));
+        assert!(yaml.contains("password: <redacted>"));
+    }
+}
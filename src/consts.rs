@@ -1,9 +1,24 @@
-pub const LOOP_PAUSE_MILLISECONDS: u64 = 250_u64;
-pub const MPSC_BUFFER_SIZE: usize = 128_usize;
-pub const GPS_PRECISION_FACTOR: f32 = 0.0000001_f32;
+pub const HOUSEKEEPING_INTERVAL: u64 = 1_u64;
+pub const BROADCAST_ADDR: u32 = 0xffffffff_u32;
+// traceroute snr is reported in quarter dB, with INT8_MIN standing in for unknown
+pub const TRACEROUTE_SNR_FACTOR: f64 = 0.25_f64;
+pub const TRACEROUTE_SNR_UNKNOWN: i32 = -128_i32;
+pub const GPS_PRECISION_FACTOR: f64 = 0.0000001_f64;
+pub const GROUND_TRACK_FACTOR: f64 = 0.00001_f64;
+pub const DOP_FACTOR: f64 = 0.01_f64;
 
-pub const DEADMAN_TIMEOUT: u64 = 300_u64;
-pub const HEARTBEAT_INTERVAL: u64 = 10_u64;
+pub const METRIC_IDLE_TIMEOUT: u64 = 600_u64;
+pub const SERIES_KEEPALIVE_INTERVAL: u64 = 60_u64;
+pub const STORE_FLUSH_INTERVAL: u64 = 5_u64;
+pub const STORE_RETENTION_INTERVAL: u64 = 3600_u64;
+pub const HISTORY_FINE_RESOLUTION: u64 = 300_u64;
+pub const HISTORY_COARSE_RESOLUTION: u64 = 3600_u64;
+pub const HISTORY_DEFAULT_WINDOW: u64 = 86400_u64;
+
+pub const RSSI_BUCKETS: &[f64] = &[-130.0, -120.0, -110.0, -100.0, -90.0, -80.0, -70.0, -60.0, -50.0, -40.0, -30.0];
+pub const HOPS_BUCKETS: &[f64] = &[0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0];
+pub const TEXT_LENGTH_BUCKETS: &[f64] = &[8.0, 16.0, 32.0, 64.0, 128.0, 192.0, 256.0];
+pub const SNR_BUCKETS: &[f64] = &[-20.0, -15.0, -10.0, -7.5, -5.0, -2.5, 0.0, 2.5, 5.0, 7.5, 10.0, 15.0];
 
 pub const LABEL_DEVICE_ID: &str = "device_id";
 pub const LABEL_DEVICE_ROLE: &str = "device_role";
@@ -14,3 +29,19 @@ pub const LABEL_LICENSED: &str = "is_licensed";
 pub const LABEL_SHORT_NAME: &str = "short_name";
 pub const LABEL_LONG_NAME: &str = "long_name";
 pub const LABEL_SENSOR_CHANNEL: &str = "sensor_channel";
+pub const LABEL_NEIGHBOR_ID: &str = "neighbor_id";
+pub const LABEL_STATE: &str = "state";
+pub const LABEL_QUEUE: &str = "queue";
+pub const LABEL_PORTNUM: &str = "portnum";
+pub const LABEL_REASON: &str = "reason";
+pub const LABEL_CHANNEL: &str = "channel";
+pub const LABEL_DIRECTION: &str = "direction";
+pub const LABEL_HOP: &str = "hop";
+pub const LABEL_HOP_ID: &str = "hop_id";
+pub const LABEL_KIND: &str = "kind";
+
+pub const DIRECTION_TOWARDS: &str = "towards";
+pub const DIRECTION_BACK: &str = "back";
+
+pub const QUEUE_FROM_RADIO: &str = "from_radio";
+pub const QUEUE_TO_RADIO: &str = "to_radio";
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use serde_json::{json, Value};
+use crate::consts::GPS_PRECISION_FACTOR;
+use crate::node_db::{Node, NodePosition};
+use crate::app_metrics;
+
+// Nodes can share a deliberately vague position; precision_bits is how many of the high bits
+// of latitude_i/longitude_i are real, so we mask off the rest and sit in the middle of what's left
+// like the firmware does.  0 and 32 both mean full precision.
+fn truncate(coordinate: f64, precision_bits: u32) -> f64 {
+    if precision_bits == 0 || precision_bits >= 32 {
+        return coordinate;
+    }
+    let fixed = (coordinate / GPS_PRECISION_FACTOR).round() as i32 as u32;
+    let masked = (fixed & (u32::MAX << (32 - precision_bits))) + (1_u32 << (31 - precision_bits));
+    masked as i32 as f64 * GPS_PRECISION_FACTOR
+}
+
+// [longitude, latitude], the order geojson wants
+fn coordinates(position: &NodePosition) -> Value {
+    json!([
+        truncate(position.longitude, position.precision_bits),
+        truncate(position.latitude, position.precision_bits),
+    ])
+}
+
+pub fn feature_collection(nodes: &[Node]) -> Value {
+    let mut features = vec![];
+    let positions: HashMap<&str, &NodePosition> = nodes.iter()
+        .filter_map(|n| n.position.as_ref().map(|p| (n.id.as_str(), p)))
+        .collect();
+    for node in nodes.iter() {
+        let Some(position) = &node.position else { continue };
+        features.push(json!({
+            "type": "Feature",
+            "id": node.id,
+            "geometry": {
+                "type": "Point",
+                "coordinates": coordinates(position),
+            },
+            "properties": {
+                "id": node.id,
+                "short_name": node.short_name,
+                "long_name": node.long_name,
+                "hw_model": node.hw_model,
+                "role": node.role,
+                "battery": node.telemetry.get(app_metrics::METRIC_BATTERY),
+                "last_heard": node.last_heard,
+                "hops_away": node.hops_away,
+                "altitude": position.altitude,
+                "precision_bits": position.precision_bits,
+                "position_time": position.time,
+            },
+        }));
+    }
+    // one line per reported link, so a pair that hear each other shows up in both directions
+    for node in nodes.iter() {
+        let Some(from) = positions.get(node.id.as_str()) else { continue };
+        for (neighbor_id, snr) in node.neighbors.iter() {
+            let Some(to) = positions.get(neighbor_id.as_str()) else { continue };
+            features.push(json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "LineString",
+                    "coordinates": [coordinates(from), coordinates(to)],
+                },
+                "properties": {
+                    "from": node.id,
+                    "to": neighbor_id,
+                    "snr": snr,
+                },
+            }));
+        }
+    }
+    json!({
+        "type": "FeatureCollection",
+        "features": features,
+    })
+}
@@ -0,0 +1,113 @@
+use std::net::SocketAddr;
+use anyhow::Result;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Json, Router};
+use metrics_exporter_prometheus::PrometheusHandle;
+use tokio::net::TcpListener;
+use crate::structs::RadioState;
+use serde::Deserialize;
+use crate::consts::HISTORY_DEFAULT_WINDOW;
+use crate::{geojson, lifecycle, node_db, store, DEAD_MAN_SWITCH, RADIO_STATE, SETTINGS};
+use crate::meshtastic_interaction::parse_node_id;
+
+pub(crate) async fn bind(addr: SocketAddr) -> Result<TcpListener> {
+    Ok(TcpListener::bind(addr).await?)
+}
+
+pub(crate) async fn serve(listener: TcpListener, prometheus: PrometheusHandle) -> Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/api/nodes", get(nodes))
+        .route("/api/nodes.geojson", get(nodes_geojson))
+        .route("/api/nodes/:id", get(node))
+        .route("/api/nodes/:id/history", get(node_history))
+        .with_state(prometheus);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn metrics(State(prometheus): State<PrometheusHandle>) -> impl IntoResponse {
+    // ages are only meaningful at the moment they're read, so compute them per scrape
+    lifecycle::record_ages().await;
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        prometheus.render(),
+    )
+}
+
+async fn healthz() -> impl IntoResponse {
+    (StatusCode::OK, "ok")
+}
+
+// ready once configure() has finished and packets are still arriving within the deadman window
+async fn readyz() -> impl IntoResponse {
+    let state = *RADIO_STATE.read().await;
+    if state != RadioState::Connected {
+        return (StatusCode::SERVICE_UNAVAILABLE, format!("radio is {state}"));
+    }
+    let deadman_timeout = SETTINGS.read().await.deadman_timeout;
+    let since_last_packet = crate::get_secs().saturating_sub(*DEAD_MAN_SWITCH.read().await);
+    if since_last_packet > deadman_timeout {
+        return (StatusCode::SERVICE_UNAVAILABLE, format!("no packet in {since_last_packet} seconds"));
+    }
+    (StatusCode::OK, "ok".to_string())
+}
+
+async fn nodes() -> impl IntoResponse {
+    Json(node_db::all().await)
+}
+
+async fn nodes_geojson() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "application/geo+json")],
+        Json(geojson::feature_collection(&node_db::all().await)),
+    )
+}
+
+// accepts the id with or without its leading !
+async fn node(Path(id): Path<String>) -> impl IntoResponse {
+    let Some(num) = parse_node_id(&id) else {
+        return (StatusCode::BAD_REQUEST, format!("{id} isn't a device id")).into_response();
+    };
+    match node_db::get(&format!("!{:x}", num)).await {
+        Some(node) => Json(node).into_response(),
+        None => (StatusCode::NOT_FOUND, format!("no node {id}")).into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryParams {
+    metric: String,
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+// ?metric=voltage&from=<unix secs>&to=<unix secs>, the last day if from and to are left off
+async fn node_history(Path(id): Path<String>, Query(params): Query<HistoryParams>) -> impl IntoResponse {
+    let config = SETTINGS.read().await.store.clone();
+    if !config.enabled {
+        return (StatusCode::NOT_FOUND, "history needs the store enabled".to_string()).into_response();
+    }
+    let Some(num) = parse_node_id(&id) else {
+        return (StatusCode::BAD_REQUEST, format!("{id} isn't a device id")).into_response();
+    };
+    let to = params.to.unwrap_or_else(crate::get_secs);
+    let from = params.from.unwrap_or(to.saturating_sub(HISTORY_DEFAULT_WINDOW));
+    if from > to {
+        return (StatusCode::BAD_REQUEST, "from is after to".to_string()).into_response();
+    }
+    let device_id = format!("!{:x}", num);
+    let result = tokio::task::spawn_blocking(move || {
+        store::history(&config, &device_id, &params.metric, from, to)
+    }).await;
+    match result {
+        Ok(Ok(history)) => Json(history).into_response(),
+        Ok(Err(e)) => (StatusCode::INTERNAL_SERVER_ERROR, format!("couldn't read history: {e}")).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("couldn't read history: {e}")).into_response(),
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use lazy_static::lazy_static;
+use metrics::{counter, gauge};
+use tokio::sync::RwLock;
+use crate::{app_metrics, consts, node_db, series};
+
+lazy_static! {
+    // device_id -> unix time we last heard from it
+    static ref LAST_HEARD: RwLock<HashMap<String, u64>> = RwLock::new(HashMap::new());
+}
+
+pub async fn heard(device_id: &str, at: u64) {
+    let latest;
+    {
+        let mut last_heard = LAST_HEARD.write().await;
+        let entry = last_heard.entry(device_id.to_string()).or_insert(at);
+        if at > *entry {
+            *entry = at;
+        }
+        latest = *entry;
+    }
+    let labels = vec![
+        (consts::LABEL_DEVICE_ID, device_id.to_string())
+    ];
+    gauge!(app_metrics::METRIC_LAST_HEARD_TIMESTAMP, &labels).set(latest as f64);
+    node_db::heard(device_id, latest).await;
+}
+
+pub async fn known_nodes() -> Vec<String> {
+    LAST_HEARD.read().await.keys().cloned().collect()
+}
+
+pub async fn record_ages() {
+    let now = crate::get_secs();
+    let last_heard = LAST_HEARD.read().await;
+    for (device_id, at) in last_heard.iter() {
+        let labels = vec![
+            (consts::LABEL_DEVICE_ID, device_id.clone())
+        ];
+        gauge!(app_metrics::METRIC_LAST_HEARD_AGE, &labels).set(now.saturating_sub(*at) as f64);
+    }
+}
+
+pub async fn expire_nodes(ttl: u64) {
+    let now = crate::get_secs();
+    let expired: Vec<String>;
+    {
+        let mut last_heard = LAST_HEARD.write().await;
+        if ttl > 0 {
+            expired = last_heard.iter()
+                .filter(|(_, at)| now.saturating_sub(**at) > ttl)
+                .map(|(id, _)| id.clone())
+                .collect();
+            for device_id in expired.iter() {
+                last_heard.remove(device_id);
+            }
+        } else {
+            expired = vec![];
+        }
+        gauge!(app_metrics::METRIC_NODES_KNOWN).set(last_heard.len() as f64);
+    }
+    for device_id in expired.iter() {
+        node_db::remove(device_id).await;
+        let retired = series::retire(|key| {
+            series::label_value(key, consts::LABEL_DEVICE_ID) == Some(device_id.as_str())
+                || series::label_value(key, consts::LABEL_NEIGHBOR_ID) == Some(device_id.as_str())
+        });
+        info!("Haven't heard from {device_id} in over {ttl} seconds, retiring its {retired} series.");
+        counter!(app_metrics::METRIC_NODE_EXPIRED).increment(1);
+    }
+}
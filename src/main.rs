@@ -3,6 +3,17 @@ mod structs;
 mod consts;
 mod app_metrics;
 mod processing;
+mod mqtt;
+mod series;
+mod lifecycle;
+mod http;
+mod message_log;
+mod traceroute;
+mod polling;
+mod config;
+mod node_db;
+mod geojson;
+mod store;
 
 
 #[macro_use]
@@ -11,25 +22,31 @@ extern crate tokio;
 extern crate tracing;
 
 use std::collections::HashMap;
-use crate::meshtastic_interaction::meshtastic_loop;
+use crate::meshtastic_interaction::supervise;
+use clap::Parser;
 use std::fs;
 use std::net::SocketAddr;
 use std::ops::Mul;
-use crate::structs::{AppConfig, Connection, IPCMessage};
+use crate::structs::{AppConfig, Connection, IPCMessage, RadioState};
 use std::process;
 use lazy_static::lazy_static;
-use metrics_exporter_prometheus::PrometheusBuilder;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
 use serde::Deserialize;
-use tokio::sync::{mpsc, OnceCell, RwLock};
+use tokio::sync::{mpsc, RwLock};
+use tokio_util::sync::CancellationToken;
 use tracing_subscriber::EnvFilter;
 use metrics_util::MetricKindMask;
+use metrics_util::layers::{PrefixLayer, Stack};
 use std::time;
 use ::metrics::{counter, gauge};
 use tokio::task::JoinHandle;
-use crate::consts::{GPS_PRECISION_FACTOR, HEARTBEAT_INTERVAL, LABEL_SENSOR_CHANNEL, LOOP_PAUSE_MILLISECONDS};
+use crate::consts::{GPS_PRECISION_FACTOR, HOUSEKEEPING_INTERVAL, LABEL_SENSOR_CHANNEL, QUEUE_FROM_RADIO, QUEUE_TO_RADIO, SERIES_KEEPALIVE_INTERVAL};
+use crate::series::SeriesLayer;
 use anyhow::Result;
 use meshtastic::protobufs::from_radio::PayloadVariant;
-use meshtastic::protobufs::{DeviceMetadata, MeshPacket, MyNodeInfo, NodeInfo, PortNum, Position, Telemetry, to_radio, ToRadio, User, Heartbeat};
+use meshtastic::protobufs::{DeviceMetadata, MeshPacket, MyNodeInfo, NodeInfo, PortNum, Position, Telemetry, User};
 use meshtastic::protobufs::mesh_packet::PayloadVariant as mp_variant;
 use strum::Display;
 use crate::app_metrics::*;
@@ -46,28 +63,13 @@ pub fn die(msg: &str) {
 
 
 lazy_static! {
-      static ref SHUTDOWN: OnceCell<bool> = OnceCell::new();
-          //region create SETTINGS static object
-      static ref SETTINGS: RwLock<AppConfig> = RwLock::new({
-           let cfg_file = match std::env::var("CONFIG_FILE_PATH") {
-               Ok(s) => s,
-               Err(_e) => { "./config.yaml".to_string()}
-           };
-          let yaml = fs::read_to_string(cfg_file).unwrap_or_else(|e| {
-              die(&format!("Can't read config file: {e}"));
-              String::default()
-              });
-          let gc: AppConfig = match serde_yaml::from_str(&yaml)  {
-              Ok(gc) => gc,
-              Err(e) => { die(&format!("Couldn't deserialize AppConfig: {e}"));
-              AppConfig::default()}
-          };
-          gc
-      });
-      //endregion
+      static ref SHUTDOWN: CancellationToken = CancellationToken::new();
+      // filled in by main from config::load before anything else reads it
+      static ref SETTINGS: RwLock<AppConfig> = RwLock::new(AppConfig::default());
 
     // I'm using a static ref here but I don't necessarily need to, yet.
       static ref DEAD_MAN_SWITCH: RwLock<u64> = RwLock::new(0_u64);
+      static ref RADIO_STATE: RwLock<RadioState> = RwLock::new(RadioState::Disconnected);
   }
 
 #[tokio::main]
@@ -75,100 +77,253 @@ pub async fn main() {
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
         .init();
-    let config: AppConfig;
+    //region load configuration
+    let cli = config::Cli::parse();
+    let config = match config::load(&cli) {
+        Ok(c) => c,
+        Err(e) => {
+            die(&format!("Couldn't load configuration: {e:#}"));
+            return;
+        }
+    };
+    if cli.print_config {
+        match config::redacted_yaml(&config) {
+            Ok(yaml) => print!("{yaml}"),
+            Err(e) => die(&format!("Couldn't serialize configuration: {e}")),
+        }
+        return;
+    }
     {
-        config = SETTINGS.read().await.clone();
+        *SETTINGS.write().await = config.clone();
+    }
+    //endregion
+
+    if let Err(e) = config.validate() {
+        die(&format!("Invalid configuration: {e}"));
+    }
+
+    if let Err(e) = message_log::init(&config.message_log) {
+        die(&format!("Couldn't open message log {}: {e}", config.message_log.path));
     }
 
-    let conn = Connection::TCP(config.meshtastic_addr.ip().to_string(), config.meshtastic_addr.port());
+
+    let conn = config.connection();
+    // mqtt is receive only, there's no radio to send a traceroute or poll from
+    let mut traceroute_config = config.traceroute.clone();
+    if traceroute_config.enabled && matches!(conn, Connection::Mqtt(_)) {
+        warn!("Traceroutes can't be sent over an mqtt connection, disabling them.");
+        traceroute_config.enabled = false;
+    }
+    let mut polling_config = config.polling.clone();
+    if polling_config.enabled && matches!(conn, Connection::Mqtt(_)) {
+        warn!("Nodes can't be polled over an mqtt connection, disabling polling.");
+        polling_config.enabled = false;
+    }
 
     //region create prometheus registry
     let metrics_addr: SocketAddr = format!("0.0.0.0:{}", config.metrics_port).parse().unwrap();
-    let prometheus_builder = PrometheusBuilder::new().idle_timeout(
-        MetricKindMask::COUNTER | MetricKindMask::HISTOGRAM,
-        Some(time::Duration::from_secs(10)),
-    )
-        .with_http_listener(metrics_addr)
-        .install().expect("Couldn't start prometheus.");
+    let recorder = prometheus_builder(&config).build_recorder();
+    let prometheus_handle = recorder.handle();
+    // metric names are namespaced by the recorder rather than at every call site
+    if config.namespace.is_empty() {
+        Stack::new(recorder)
+            .push(SeriesLayer)
+            .install().expect("Couldn't install metrics recorder.");
+    } else {
+        Stack::new(recorder)
+            .push(PrefixLayer::new(config.namespace.clone()))
+            .push(SeriesLayer)
+            .install().expect("Couldn't install metrics recorder.");
+    }
     register_metrics();
     //endregion
 
+    if let Err(e) = store::open(&config.store, config.node_ttl).await {
+        die(&format!("Couldn't open store {}: {e}", config.store.path));
+    }
+
+    //region start http listener
+    let listener = http::bind(metrics_addr).await.expect("Couldn't start http listener.");
+    let http_prometheus_handle = prometheus_handle.clone();
+    tokio::task::spawn(async move {
+        if let Err(e) = http::serve(listener, http_prometheus_handle).await {
+            error!("Http listener failed: {e}");
+            SHUTDOWN.cancel();
+        }
+    });
+    //endregion
+
     //region spawn meshtastic connection thread
     let (fromradio_thread_tx, mut fromradio_thread_rx) =
-        mpsc::channel::<IPCMessage>(consts::MPSC_BUFFER_SIZE);
+        mpsc::channel::<IPCMessage>(config.channel_buffer_size);
     let (toradio_thread_tx, toradio_thread_rx) =
-        mpsc::channel::<IPCMessage>(consts::MPSC_BUFFER_SIZE);
+        mpsc::channel::<IPCMessage>(config.channel_buffer_size);
     let fromradio_tx = fromradio_thread_tx.clone();
-    let join_handle: JoinHandle<Result<()>> = tokio::task::spawn(async move {
-        meshtastic_loop(conn, fromradio_tx, toradio_thread_rx).await
+    let mut join_handle: JoinHandle<Result<()>> = tokio::task::spawn(async move {
+        supervise(conn, fromradio_tx, toradio_thread_rx).await
     });
     //endregion
 
     //initialize deadman with current time
     update_deadman().await;
 
-    let mut last_heartbeat :u64 = get_secs();
-
-    while SHUTDOWN.get().is_none() {
-        if let Ok(packet) = fromradio_thread_rx.try_recv() {
-            update_deadman().await;
-            if let IPCMessage::FromRadio(inbound_packet) = packet {
-                if let Some(fromradio_variant) = inbound_packet.payload_variant {
-                    match fromradio_variant {
-                        PayloadVariant::Packet(mesh_packet) => processing::process_mesh_packet(&mesh_packet).await,
-                        PayloadVariant::MyInfo(my_info) => processing::process_my_info(&my_info).await,
-                        PayloadVariant::NodeInfo(node_info) => processing::process_node_info(&node_info).await,
-                        PayloadVariant::Metadata(metadata) => processing::process_metadata(&metadata).await,
-                        _ => {}
+    //region listen for termination signals
+    let mut signals = Signals::new([SIGTERM, SIGINT]).expect("Couldn't register signal handlers.");
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            if SHUTDOWN.is_cancelled() {
+                warn!("Received signal {signal} while already shutting down, exiting immediately.");
+                process::exit(1);
+            }
+            info!("Received signal {signal}, shutting down.");
+            SHUTDOWN.cancel();
+        }
+    });
+    //endregion
+
+    let mut housekeeping = tokio::time::interval(time::Duration::from_secs(HOUSEKEEPING_INTERVAL));
+    let mut series_keepalive = tokio::time::interval(time::Duration::from_secs(SERIES_KEEPALIVE_INTERVAL));
+    loop {
+        // checked in order: a supervisor that returns because we're shutting down has already
+        // cancelled SHUTDOWN, and must go through the drain rather than the exit below
+        select! {
+            biased;
+            _ = SHUTDOWN.cancelled() => break,
+            //region thread tending
+            result = &mut join_handle => {
+                match result {
+                    Ok(o) => {
+                        match o {
+                            Ok(_) => {
+                                error!("Meshtastic supervisor exited gracefully.  Shouldn't happen");
+                            }
+                            Err(e) => {
+                                error!("Exiting, meshtastic supervisor error: {e}");
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        error!("Meshtastic supervisor died, and we don't know why: {e}");
+                    }
+                }
+                process::exit(2);
+            }
+            //endregion
+            _ = housekeeping.tick() => {
+                let since_last_packet = get_secs().saturating_sub(*DEAD_MAN_SWITCH.read().await);
+                gauge!(METRIC_SECS_SINCE_LAST_PACKET).set(since_last_packet as f64);
+                gauge!(METRIC_DEADMAN_EXPIRED).set(if since_last_packet > config.deadman_timeout { 1.0 } else { 0.0 });
+                record_queue_depth(&fromradio_thread_tx, QUEUE_FROM_RADIO);
+                record_queue_depth(&toradio_thread_tx, QUEUE_TO_RADIO);
+                prometheus_handle.run_upkeep();
+                if let Some(probe) = traceroute::next_probe(&traceroute_config).await {
+                    if let Err(e) = toradio_thread_tx.try_send(probe) {
+                        warn!("Couldn't queue traceroute for the radio: {e}");
+                    }
+                }
+                if let Some(request) = polling::next_request(&polling_config).await {
+                    if let Err(e) = toradio_thread_tx.try_send(request) {
+                        warn!("Couldn't queue poll request for the radio: {e}");
                     }
                 }
             }
+            _ = series_keepalive.tick() => {
+                lifecycle::expire_nodes(config.node_ttl).await;
+                series::keepalive();
+            }
+            Some(packet) = fromradio_thread_rx.recv() => {
+                process_packet(packet).await;
+                counter!(METRIC_PACKETS_PROCESSED).increment(1);
+                record_queue_depth(&fromradio_thread_tx, QUEUE_FROM_RADIO);
+            }
         }
+    };
 
-        let heartbeat_diff = get_secs().saturating_sub(last_heartbeat);
-        if heartbeat_diff > HEARTBEAT_INTERVAL {
-            let tr = ToRadio {
-                payload_variant: Some(to_radio::PayloadVariant::Heartbeat(Heartbeat::default()))
-            };
-            if let Err(e) = toradio_thread_tx.send(IPCMessage::ToRadio(tr)).await {
-                error!("Could not send heartbeat packet.  Exiting.");
-                SHUTDOWN.set(true).expect("Couldn't set shutdown, so, wanted to shutdown anyway")
-            } else {
-                last_heartbeat = get_secs();
+    //region drain
+    info!("Waiting up to {} seconds for the radio connection to close.", config.drain_timeout);
+    let drain_timeout = tokio::time::sleep(time::Duration::from_secs(config.drain_timeout));
+    tokio::pin!(drain_timeout);
+    loop {
+        select! {
+            result = &mut join_handle => {
+                match result {
+                    Ok(Err(e)) => warn!("Meshtastic supervisor exited with error during shutdown: {e}"),
+                    Err(e) => warn!("Meshtastic supervisor died during shutdown: {e}"),
+                    Ok(Ok(_)) => {}
+                }
+                break;
+            }
+            // the connection can be stuck handing us a packet on a full queue, and won't see
+            // the shutdown until it's taken
+            Some(packet) = fromradio_thread_rx.recv() => {
+                process_packet(packet).await;
+            }
+            _ = &mut drain_timeout => {
+                warn!("Radio connection didn't close within the drain timeout, abandoning it.");
+                join_handle.abort();
+                break;
             }
         }
+    }
+    drop(toradio_thread_tx);
+    while let Ok(packet) = fromradio_thread_rx.try_recv() {
+        process_packet(packet).await;
+    }
+    message_log::flush();
+    store::close();
+    //endregion
+    info!("After a while, crocodile");
+}
 
-        //region thread tending
-        if join_handle.is_finished() {
-            let result = join_handle.await;
-            match result {
-                Ok(o) => {
-                    match o {
-                        Ok(_) => {
-                            error!("Meshtastic thread exited gracefully.  Shouldn't happen");
-                        }
-                        Err(e) => {
-                            error!("Exiting, meshtastic error: {e}");
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("We lost connection to meshtastic device, and we don't know why");
-                }
+fn record_queue_depth(tx: &mpsc::Sender<IPCMessage>, queue: &str) {
+    let labels = vec![
+        (consts::LABEL_QUEUE, queue.to_string())
+    ];
+    gauge!(METRIC_QUEUE_DEPTH, &labels).set((tx.max_capacity() - tx.capacity()) as f64);
+}
+
+async fn process_packet(packet: IPCMessage) {
+    if let IPCMessage::FromRadio(inbound_packet) = packet {
+        if let Some(fromradio_variant) = inbound_packet.payload_variant {
+            match fromradio_variant {
+                PayloadVariant::Packet(mesh_packet) => processing::process_mesh_packet(&mesh_packet).await,
+                PayloadVariant::MyInfo(my_info) => processing::process_my_info(&my_info).await,
+                PayloadVariant::NodeInfo(node_info) => processing::process_node_info(&node_info).await,
+                PayloadVariant::Metadata(metadata) => processing::process_metadata(&metadata).await,
+                _ => {}
             }
-            process::exit(2);
         }
-        //endregion
+    }
+}
 
-        check_deadman().await;
 
-        //region sleep to free up processor
-        tokio::time::sleep(time::Duration::from_millis(LOOP_PAUSE_MILLISECONDS)).await;
-        //endregion
-    };
-    info!("After a while, crocodile");
+fn prometheus_builder(config: &AppConfig) -> PrometheusBuilder {
+    let mut prometheus_builder = PrometheusBuilder::new().idle_timeout(
+        MetricKindMask::ALL,
+        Some(time::Duration::from_secs(consts::METRIC_IDLE_TIMEOUT)),
+    )
+        .set_buckets_for_metric(Matcher::Full(metric_name(config, METRIC_RSSI_HISTOGRAM)), consts::RSSI_BUCKETS)
+        .expect("Couldn't set RSSI histogram buckets.")
+        .set_buckets_for_metric(Matcher::Full(metric_name(config, METRIC_SNR_HISTOGRAM)), consts::SNR_BUCKETS)
+        .expect("Couldn't set SNR histogram buckets.")
+        .set_buckets_for_metric(Matcher::Full(metric_name(config, METRIC_HOPS_HISTOGRAM)), consts::HOPS_BUCKETS)
+        .expect("Couldn't set hops histogram buckets.")
+        .set_buckets_for_metric(Matcher::Full(metric_name(config, METRIC_TEXT_MESSAGE_LENGTH)), consts::TEXT_LENGTH_BUCKETS)
+        .expect("Couldn't set text message length histogram buckets.");
+    for (label, value) in config.global_labels.iter() {
+        prometheus_builder = prometheus_builder.add_global_label(label, value);
+    }
+    prometheus_builder
 }
 
+// The name a bucket matcher sees: the prefix layer joins the namespace on with a dot, and the
+// exporter sanitizes that to an underscore before matching.
+fn metric_name(config: &AppConfig, name: &str) -> String {
+    match config.namespace.is_empty() {
+        true => name.to_string(),
+        false => format!("{}_{}", config.namespace, name)
+    }
+}
 
 pub fn get_secs() -> u64 {
     SystemTime::now()
@@ -182,12 +337,45 @@ pub async fn update_deadman() {
     *dms = get_secs();
 }
 
-pub async fn check_deadman() {
-    let dms = DEAD_MAN_SWITCH.read().await;
-    let now = get_secs();
-    let diff = now.saturating_sub(*dms);
-    if diff > consts::DEADMAN_TIMEOUT {
-        error!("Deadman switch timer elapsed, we haven't received a packet in {diff} seconds. closing app.");
-        SHUTDOWN.set(true).expect("Couldn't set shutdown so.. panic it is");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::metrics::histogram;
+    use metrics_util::layers::Layer;
+
+    fn render_histograms(config: &AppConfig) -> String {
+        let recorder = prometheus_builder(config).build_recorder();
+        let handle = recorder.handle();
+        let record = || {
+            histogram!(METRIC_RSSI_HISTOGRAM).record(-97.0);
+            histogram!(METRIC_SNR_HISTOGRAM).record(5.25);
+            histogram!(METRIC_HOPS_HISTOGRAM).record(2.0);
+            histogram!(METRIC_TEXT_MESSAGE_LENGTH).record(42.0);
+        };
+        match config.namespace.is_empty() {
+            true => ::metrics::with_local_recorder(&recorder, record),
+            false => ::metrics::with_local_recorder(&PrefixLayer::new(config.namespace.clone()).layer(recorder), record),
+        }
+        handle.render()
+    }
+
+    #[tokio::test]
+    async fn histograms_get_buckets_with_a_namespace() {
+        let output = render_histograms(&AppConfig::default());
+        for name in [METRIC_RSSI_HISTOGRAM, METRIC_SNR_HISTOGRAM, METRIC_HOPS_HISTOGRAM, METRIC_TEXT_MESSAGE_LENGTH] {
+            assert!(output.contains(&format!("meshtastic_{name}_bucket{{le=")), "{name} isn't a histogram:\n{output}");
+        }
+    }
+
+    #[tokio::test]
+    async fn histograms_get_buckets_without_a_namespace() {
+        let config = AppConfig {
+            namespace: String::new(),
+            ..Default::default()
+        };
+        let output = render_histograms(&config);
+        for name in [METRIC_RSSI_HISTOGRAM, METRIC_SNR_HISTOGRAM, METRIC_HOPS_HISTOGRAM, METRIC_TEXT_MESSAGE_LENGTH] {
+            assert!(output.contains(&format!("\n{name}_bucket{{le=")), "{name} isn't a histogram:\n{output}");
+        }
     }
 }
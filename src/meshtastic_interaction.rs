@@ -1,15 +1,18 @@
 use crate::structs::IPCMessage;
-use crate::structs::Connection;
+use crate::structs::{Connection, DeadmanAction, RadioState};
 use anyhow::{bail, Result};
+use std::time::{Duration, Instant};
 
 use meshtastic::packet::PacketRouter;
 
-use meshtastic::protobufs::{FromRadio, MeshPacket};
+use meshtastic::protobufs::{mesh_packet, to_radio, Data, FromRadio, Heartbeat, MeshPacket, PortNum, ToRadio};
 use meshtastic::types::NodeId;
 use meshtastic::{api::StreamApi, utils};
-use strum::Display;
+use metrics::{counter, gauge};
+use rand::Rng;
+use strum::{Display, IntoEnumIterator};
 use thiserror::Error;
-use crate::consts::LOOP_PAUSE_MILLISECONDS;
+use crate::{app_metrics, consts, RADIO_STATE, SETTINGS, SHUTDOWN};
 
 #[allow(dead_code)]
 #[derive(Display, Clone, Debug, Error)]
@@ -57,11 +60,79 @@ impl PacketRouter<(), DeviceUpdateError> for MyPacketRouter {
     }
 }
 
-pub(crate) async fn meshtastic_loop(
+pub(crate) async fn supervise(
     connection: Connection,
     tx: tokio::sync::mpsc::Sender<IPCMessage>,
     mut rx: tokio::sync::mpsc::Receiver<IPCMessage>,
 ) -> Result<()> {
+    let (backoff_initial, backoff_max, stable_secs) = {
+        let config = SETTINGS.read().await;
+        (config.reconnect_backoff_initial_ms, config.reconnect_backoff_max_ms, config.reconnect_stable_secs)
+    };
+    let mut backoff = backoff_initial;
+    while !SHUTDOWN.is_cancelled() {
+        let started = Instant::now();
+        match meshtastic_loop(connection.clone(), tx.clone(), &mut rx).await {
+            Ok(_) => info!("Meshtastic connection closed."),
+            Err(e) => error!("Meshtastic connection failed: {e}"),
+        }
+        set_radio_state(RadioState::Disconnected).await;
+        if SHUTDOWN.is_cancelled() {
+            break;
+        }
+        if started.elapsed() > Duration::from_secs(stable_secs) {
+            backoff = backoff_initial;
+        }
+        // equal jitter, so a rebooting node isn't hit by every client at the same moment
+        let delay = backoff / 2 + rand::thread_rng().gen_range(0..=backoff / 2);
+        warn!("Reconnecting to meshtastic node in {delay}ms");
+        select! {
+            _ = SHUTDOWN.cancelled() => break,
+            _ = tokio::time::sleep(Duration::from_millis(delay)) => {}
+        }
+        backoff = backoff.saturating_mul(2).min(backoff_max);
+        counter!(app_metrics::METRIC_RADIO_RECONNECTS).increment(1);
+    }
+    Ok(())
+}
+
+pub(crate) async fn set_radio_state(state: RadioState) {
+    {
+        let mut radio_state = RADIO_STATE.write().await;
+        *radio_state = state;
+    }
+    for s in RadioState::iter() {
+        let labels = vec![
+            (consts::LABEL_STATE, s.to_string())
+        ];
+        gauge!(app_metrics::METRIC_RADIO_STATE, &labels).set(if s == state { 1.0 } else { 0.0 });
+    }
+}
+
+// Ok(true) to carry on with the connection, Ok(false) to close it because we're shutting down
+pub(crate) fn deadman_expired(action: DeadmanAction, timeout: u64) -> Result<bool> {
+    match action {
+        DeadmanAction::Reconnect => {
+            bail!("Deadman switch timer elapsed, we haven't received a packet in {timeout} seconds.");
+        }
+        DeadmanAction::Exit => {
+            error!("Deadman switch timer elapsed, we haven't received a packet in {timeout} seconds, shutting down.");
+            SHUTDOWN.cancel();
+            Ok(false)
+        }
+        DeadmanAction::Unhealthy => {
+            warn!("Deadman switch timer elapsed, we haven't received a packet in {timeout} seconds.");
+            Ok(true)
+        }
+    }
+}
+
+pub(crate) async fn meshtastic_loop(
+    connection: Connection,
+    tx: tokio::sync::mpsc::Sender<IPCMessage>,
+    rx: &mut tokio::sync::mpsc::Receiver<IPCMessage>,
+) -> Result<()> {
+    set_radio_state(RadioState::Connecting).await;
     let stream_api = StreamApi::new();
     let mut decoded_listener;
     let connected_stream_api;
@@ -75,37 +146,105 @@ pub(crate) async fn meshtastic_loop(
             };
             (decoded_listener, connected_stream_api) = stream_api.connect(tcp_stream).await;
         }
-        Connection::Serial(device) => {
-            let serial_stream = utils::stream::build_serial_stream(device, None, None, None)
-                .expect("Unable to open serial port.");
+        Connection::Serial(serial) => {
+            let serial_stream = match utils::stream::build_serial_stream(serial.device.clone(), serial.baud_rate, serial.dtr, serial.rts) {
+                Ok(sh) => sh,
+                Err(e) => {
+                    bail!("Unable to open serial port {}: {e} (check the device path, and that we're allowed to open it, e.g. membership in the dialout group)", serial.device);
+                }
+            };
             (decoded_listener, connected_stream_api) = stream_api.connect(serial_stream).await;
         }
+        Connection::Mqtt(mqtt) => {
+            return crate::mqtt::mqtt_loop(mqtt, tx, rx).await;
+        }
         Connection::None => {
-            panic!("Neither tcp nor serial selected for connection.");
+            panic!("Neither tcp, serial nor mqtt selected for connection.");
         }
     }
+    set_radio_state(RadioState::Configuring).await;
     let config_id = utils::generate_rand_id();
     let mut _stream_api = connected_stream_api.configure(config_id).await?;
     info!("Connected to meshtastic node!");
+    set_radio_state(RadioState::Connected).await;
+    crate::update_deadman().await;
     let mut packet_router = MyPacketRouter::new(0);
+    let (heartbeat_interval, deadman_timeout, deadman_action) = {
+        let config = SETTINGS.read().await;
+        (config.heartbeat_interval, config.deadman_timeout, config.deadman_action)
+    };
+    let mut heartbeat = tokio::time::interval(Duration::from_secs(heartbeat_interval));
+    let deadman = tokio::time::sleep(Duration::from_secs(deadman_timeout));
+    tokio::pin!(deadman);
     loop {
-        if let Ok(fr) = decoded_listener.try_recv() {
-            if let Err(e) = tx.send(IPCMessage::FromRadio(fr)).await {
-                bail!("Couldn't send FromRadio packet to mpsc: {e}");
+        select! {
+            _ = SHUTDOWN.cancelled() => break,
+            fr = decoded_listener.recv() => {
+                let Some(fr) = fr else {
+                    bail!("Meshtastic node closed the connection.");
+                };
+                crate::update_deadman().await;
+                deadman.as_mut().reset(tokio::time::Instant::now() + Duration::from_secs(deadman_timeout));
+                if let Err(e) = tx.send(IPCMessage::FromRadio(fr)).await {
+                    bail!("Couldn't send FromRadio packet to mpsc: {e}");
+                }
             }
-        }
-        if let Ok(inbound) = rx.try_recv() {
-            match inbound {
-                IPCMessage::ToRadio(tr) => {
-                    if let Err(e) = _stream_api.send_to_radio_packet(tr.payload_variant).await {
-                        bail!("We tried to send a ToRadio message directly but errored: {e}");
+            Some(inbound) = rx.recv() => {
+                match inbound {
+                    IPCMessage::ToRadio(tr) => {
+                        if let Err(e) = _stream_api.send_to_radio_packet(tr.payload_variant).await {
+                            bail!("We tried to send a ToRadio message directly but errored: {e}");
+                        }
+                    }
+                    _ => {
+                        warn!("Unknown ipc message sent into comms thread.");
                     }
                 }
-                _ => {
-                    warn!("Unknown ipc message sent into comms thread.");
+            }
+            _ = heartbeat.tick() => {
+                if let Err(e) = _stream_api.send_to_radio_packet(Some(to_radio::PayloadVariant::Heartbeat(Heartbeat::default()))).await {
+                    bail!("Could not send heartbeat packet: {e}");
+                }
+            }
+            _ = &mut deadman => {
+                if !deadman_expired(deadman_action, deadman_timeout)? {
+                    break;
                 }
+                deadman.as_mut().reset(tokio::time::Instant::now() + Duration::from_secs(deadman_timeout));
             }
         }
-        tokio::time::sleep(tokio::time::Duration::from_millis(LOOP_PAUSE_MILLISECONDS)).await;
     }
+    info!("Disconnecting from meshtastic node.");
+    if let Err(e) = _stream_api.send_to_radio_packet(Some(to_radio::PayloadVariant::Disconnect(true))).await {
+        warn!("Couldn't tell the node we're disconnecting: {e}");
+    }
+    if let Err(e) = _stream_api.disconnect().await {
+        warn!("Couldn't cleanly disconnect from the node: {e}");
+    }
+    Ok(())
+}
+
+pub fn parse_node_id(device_id: &str) -> Option<u32> {
+    u32::from_str_radix(device_id.trim_start_matches('!'), 16).ok()
+}
+
+// a packet asking `to` to answer on `portnum`; the id comes back as the reply's request_id
+pub fn request_packet(to: u32, portnum: PortNum, payload: Vec<u8>, hop_limit: u32) -> (u32, IPCMessage) {
+    let id: u32 = utils::generate_rand_id();
+    let packet = MeshPacket {
+        to,
+        id,
+        want_ack: true,
+        hop_limit,
+        payload_variant: Some(mesh_packet::PayloadVariant::Decoded(Data {
+            portnum: portnum as i32,
+            payload,
+            want_response: true,
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    (id, IPCMessage::ToRadio(ToRadio {
+        payload_variant: Some(to_radio::PayloadVariant::Packet(packet)),
+    }))
 }
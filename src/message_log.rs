@@ -0,0 +1,114 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use anyhow::Result;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::structs::{MessageBody, MessageLogConfig};
+
+struct MessageLog {
+    config: MessageLogConfig,
+    writer: LineWriter<File>,
+    written: u64,
+}
+
+#[derive(Serialize)]
+struct LoggedMessage<'a> {
+    from: &'a str,
+    to: &'a str,
+    channel: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text_sha256: Option<String>,
+    rx_time: u64,
+}
+
+lazy_static! {
+    static ref MESSAGE_LOG: Mutex<Option<MessageLog>> = Mutex::new(None);
+}
+
+pub fn init(config: &MessageLogConfig) -> Result<()> {
+    if !config.enabled {
+        return Ok(());
+    }
+    let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+    let written = file.metadata()?.len();
+    info!("Logging text messages to {}", config.path);
+    *MESSAGE_LOG.lock().unwrap() = Some(MessageLog {
+        config: config.clone(),
+        writer: LineWriter::new(file),
+        written,
+    });
+    Ok(())
+}
+
+pub fn append(from: &str, to: &str, channel: u32, text: &str, rx_time: u64) {
+    let mut guard = MESSAGE_LOG.lock().unwrap();
+    let Some(log) = guard.as_mut() else { return };
+    let message = LoggedMessage {
+        from,
+        to,
+        channel,
+        text: match log.config.body {
+            MessageBody::Keep => Some(text),
+            _ => None,
+        },
+        text_sha256: match log.config.body {
+            MessageBody::Hash => Some(format!("{:x}", Sha256::digest(text.as_bytes()))),
+            _ => None,
+        },
+        rx_time,
+    };
+    let line = match serde_json::to_string(&message) {
+        Ok(l) => l,
+        Err(e) => {
+            warn!("Couldn't serialize text message for the message log: {e}");
+            return;
+        }
+    };
+    // a line bigger than max_bytes goes in a file of its own rather than rotating out every older one
+    if log.written > 0 && log.written + line.len() as u64 + 1 > log.config.max_bytes {
+        if let Err(e) = log.rotate() {
+            error!("Couldn't rotate message log {}: {e}", log.config.path);
+            return;
+        }
+    }
+    match writeln!(log.writer, "{line}") {
+        Ok(_) => log.written += line.len() as u64 + 1,
+        Err(e) => error!("Couldn't write to message log {}: {e}", log.config.path),
+    }
+}
+
+pub fn flush() {
+    if let Some(log) = MESSAGE_LOG.lock().unwrap().as_mut() {
+        if let Err(e) = log.writer.flush() {
+            error!("Couldn't flush message log {}: {e}", log.config.path);
+        }
+    }
+}
+
+impl MessageLog {
+    // messages.jsonl -> messages.jsonl.1 -> ... -> messages.jsonl.<max_files>, the oldest is dropped
+    fn rotate(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        let rotated = |n: usize| PathBuf::from(format!("{}.{n}", self.config.path));
+        if self.config.max_files == 0 {
+            fs::remove_file(&self.config.path)?;
+        } else {
+            let _ = fs::remove_file(rotated(self.config.max_files));
+            for n in (1..self.config.max_files).rev() {
+                if rotated(n).exists() {
+                    fs::rename(rotated(n), rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.config.path, rotated(1))?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&self.config.path)?;
+        self.writer = LineWriter::new(file);
+        self.written = 0;
+        Ok(())
+    }
+}
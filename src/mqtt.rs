@@ -0,0 +1,459 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
+use aes::{Aes128, Aes256};
+use anyhow::{bail, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use meshtastic::Message;
+use meshtastic::protobufs::{from_radio, Data, DeviceMetrics, EnvironmentMetrics, FromRadio, MeshPacket, Neighbor, NeighborInfo, PortNum, Position, ServiceEnvelope, Telemetry, User};
+use meshtastic::protobufs::mesh_packet::PayloadVariant as mp_variant;
+use meshtastic::protobufs::telemetry::Variant;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde_json::Value;
+use crate::meshtastic_interaction::{deadman_expired, set_radio_state};
+use crate::structs::{IPCMessage, MqttConfig, RadioState};
+use crate::{SETTINGS, SHUTDOWN};
+
+type Aes128Ctr = ctr::Ctr128BE<Aes128>;
+type Aes256Ctr = ctr::Ctr128BE<Aes256>;
+
+// the well-known key behind the one byte "AQ==" psk
+const DEFAULT_KEY: [u8; 16] = [
+    0xd4, 0xf1, 0xbb, 0x3a, 0x20, 0x29, 0x07, 0x59, 0xf0, 0xbc, 0xff, 0xab, 0xcf, 0x4e, 0x69, 0x01,
+];
+
+enum TopicKind {
+    Protobuf,
+    Json,
+}
+
+// remembers the last few (from, id) pairs, since every gateway that hears a packet uplinks it
+struct Dedup {
+    window: usize,
+    order: VecDeque<(u32, u32)>,
+    seen: HashSet<(u32, u32)>,
+}
+
+impl Dedup {
+    fn new(window: usize) -> Self {
+        Dedup {
+            window,
+            order: VecDeque::with_capacity(window),
+            seen: HashSet::with_capacity(window),
+        }
+    }
+
+    fn is_new(&mut self, from: u32, id: u32) -> bool {
+        if id == 0 {
+            return true;
+        }
+        if !self.seen.insert((from, id)) {
+            return false;
+        }
+        self.order.push_back((from, id));
+        if self.order.len() > self.window {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+pub(crate) async fn mqtt_loop(
+    config: MqttConfig,
+    tx: tokio::sync::mpsc::Sender<IPCMessage>,
+    rx: &mut tokio::sync::mpsc::Receiver<IPCMessage>,
+) -> Result<()> {
+    set_radio_state(RadioState::Connecting).await;
+    let mut keys: HashMap<String, Vec<u8>> = HashMap::new();
+    for (channel, psk) in config.channels.iter() {
+        let psk = match BASE64.decode(psk) {
+            Ok(p) => p,
+            Err(e) => bail!("PSK for mqtt channel {channel} isn't valid base64: {e}"),
+        };
+        if let Some(key) = expand_psk(&psk) {
+            keys.insert(channel.clone(), key);
+        }
+    }
+
+    let mut options = MqttOptions::new(config.client_id.clone(), config.host.clone(), config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_max_packet_size(64 * 1024, 64 * 1024);
+    if let Some(username) = &config.username {
+        options.set_credentials(username.clone(), config.password.clone().unwrap_or_default());
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, 64);
+    for topic in config.topics.iter() {
+        client.subscribe(topic.clone(), QoS::AtMostOnce).await?;
+    }
+
+    let (deadman_timeout, deadman_action) = {
+        let settings = SETTINGS.read().await;
+        (settings.deadman_timeout, settings.deadman_action)
+    };
+    let mut dedup = Dedup::new(config.dedup_window);
+    let deadman = tokio::time::sleep(Duration::from_secs(deadman_timeout));
+    tokio::pin!(deadman);
+    loop {
+        select! {
+            _ = SHUTDOWN.cancelled() => break,
+            event = eventloop.poll() => {
+                match event {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("Connected to mqtt broker {}:{}", config.host, config.port);
+                        set_radio_state(RadioState::Connected).await;
+                        crate::update_deadman().await;
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        let Some(mesh_packet) = decode_publish(&publish.topic, &publish.payload, &keys) else { continue };
+                        if !dedup.is_new(mesh_packet.from, mesh_packet.id) {
+                            continue;
+                        }
+                        crate::update_deadman().await;
+                        deadman.as_mut().reset(tokio::time::Instant::now() + Duration::from_secs(deadman_timeout));
+                        let fr = FromRadio {
+                            payload_variant: Some(from_radio::PayloadVariant::Packet(mesh_packet)),
+                            ..Default::default()
+                        };
+                        if let Err(e) = tx.send(IPCMessage::FromRadio(fr)).await {
+                            bail!("Couldn't send FromRadio packet to mpsc: {e}");
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => bail!("mqtt connection error: {e}"),
+                }
+            }
+            Some(_) = rx.recv() => {
+                debug!("Dropping ToRadio message, mqtt connections are receive-only.");
+            }
+            _ = &mut deadman => {
+                if !deadman_expired(deadman_action, deadman_timeout)? {
+                    break;
+                }
+                deadman.as_mut().reset(tokio::time::Instant::now() + Duration::from_secs(deadman_timeout));
+            }
+        }
+    }
+    let _ = client.disconnect().await;
+    Ok(())
+}
+
+// topics look like msh/<region>[/<more>...]/2/<e|c|json>/<channel>/<gateway>
+fn topic_kind(topic: &str) -> Option<TopicKind> {
+    let segments: Vec<&str> = topic.split('/').collect();
+    let version = segments.iter().position(|s| *s == "2")?;
+    match segments.get(version + 1) {
+        Some(&"e") | Some(&"c") => Some(TopicKind::Protobuf),
+        Some(&"json") => Some(TopicKind::Json),
+        _ => None,
+    }
+}
+
+fn decode_publish(topic: &str, payload: &[u8], keys: &HashMap<String, Vec<u8>>) -> Option<MeshPacket> {
+    match topic_kind(topic)? {
+        TopicKind::Protobuf => decode_envelope(topic, payload, keys),
+        TopicKind::Json => match decode_json(payload) {
+            Ok(p) => p,
+            Err(e) => {
+                debug!("Couldn't decode json message on {topic}: {e}");
+                None
+            }
+        },
+    }
+}
+
+fn decode_envelope(topic: &str, payload: &[u8], keys: &HashMap<String, Vec<u8>>) -> Option<MeshPacket> {
+    let envelope = match ServiceEnvelope::decode(payload) {
+        Ok(e) => e,
+        Err(e) => {
+            debug!("Couldn't decode ServiceEnvelope on {topic}: {e}");
+            return None;
+        }
+    };
+    let mut packet = envelope.packet?;
+    if let Some(mp_variant::Encrypted(encrypted)) = &packet.payload_variant {
+        let Some(key) = keys.get(&envelope.channel_id) else {
+            debug!("No PSK configured for channel {}, skipping packet from !{:x}", envelope.channel_id, packet.from);
+            return None;
+        };
+        let plaintext = decrypt(key, packet.id, packet.from, encrypted);
+        match Data::decode(plaintext.as_slice()) {
+            Ok(data) => packet.payload_variant = Some(mp_variant::Decoded(data)),
+            Err(e) => {
+                debug!("Couldn't decrypt packet from !{:x} on channel {}: {e}", packet.from, envelope.channel_id);
+                return None;
+            }
+        }
+    }
+    Some(packet)
+}
+
+// a one byte psk is an index into the default key, short keys are zero padded like the firmware does
+fn expand_psk(psk: &[u8]) -> Option<Vec<u8>> {
+    match psk.len() {
+        0 => None,
+        1 => {
+            if psk[0] == 0 {
+                return None;
+            }
+            let mut key = DEFAULT_KEY.to_vec();
+            key[15] = key[15].wrapping_add(psk[0] - 1);
+            Some(key)
+        }
+        2..=16 => {
+            let mut key = psk.to_vec();
+            key.resize(16, 0);
+            Some(key)
+        }
+        _ => {
+            let mut key = psk.to_vec();
+            key.resize(32, 0);
+            Some(key)
+        }
+    }
+}
+
+fn decrypt(key: &[u8], packet_id: u32, from: u32, ciphertext: &[u8]) -> Vec<u8> {
+    let mut nonce = [0_u8; 16];
+    nonce[0..8].copy_from_slice(&(packet_id as u64).to_le_bytes());
+    nonce[8..12].copy_from_slice(&from.to_le_bytes());
+    let mut buf = ciphertext.to_vec();
+    if key.len() == 32 {
+        Aes256Ctr::new(key.into(), &nonce.into()).apply_keystream(&mut buf);
+    } else {
+        Aes128Ctr::new(key.into(), &nonce.into()).apply_keystream(&mut buf);
+    }
+    buf
+}
+
+fn json_u32(v: &Value, field: &str) -> u32 {
+    v.get(field).and_then(Value::as_u64).unwrap_or_default() as u32
+}
+
+fn json_i32(v: &Value, field: &str) -> i32 {
+    v.get(field).and_then(Value::as_i64).unwrap_or_default() as i32
+}
+
+fn json_f32(v: &Value, field: &str) -> f32 {
+    v.get(field).and_then(Value::as_f64).unwrap_or_default() as f32
+}
+
+fn json_string(v: &Value, field: &str) -> String {
+    v.get(field).and_then(Value::as_str).unwrap_or_default().to_string()
+}
+
+// the json uplink flattens the decoded payload, so rebuild the protobuf our processors expect
+fn decode_json(payload: &[u8]) -> Result<Option<MeshPacket>> {
+    let msg: Value = serde_json::from_slice(payload)?;
+    let body = msg.get("payload").cloned().unwrap_or(Value::Null);
+    let (portnum, bytes) = match msg.get("type").and_then(Value::as_str).unwrap_or_default() {
+        "text" => (PortNum::TextMessageApp, json_string(&body, "text").into_bytes()),
+        "position" => {
+            let position = Position {
+                latitude_i: json_i32(&body, "latitude_i"),
+                longitude_i: json_i32(&body, "longitude_i"),
+                altitude: json_i32(&body, "altitude"),
+                time: json_u32(&body, "time"),
+                sats_in_view: json_u32(&body, "sats_in_view"),
+                precision_bits: json_u32(&body, "precision_bits"),
+                ground_speed: json_u32(&body, "ground_speed"),
+                ground_track: json_u32(&body, "ground_track"),
+                pdop: json_u32(&body, "PDOP"),
+                ..Default::default()
+            };
+            (PortNum::PositionApp, position.encode_to_vec())
+        }
+        "nodeinfo" => {
+            let user = User {
+                id: json_string(&body, "id"),
+                long_name: json_string(&body, "longname"),
+                short_name: json_string(&body, "shortname"),
+                hw_model: json_i32(&body, "hardware"),
+                role: json_i32(&body, "role"),
+                ..Default::default()
+            };
+            (PortNum::NodeinfoApp, user.encode_to_vec())
+        }
+        "telemetry" => {
+            let variant = if body.get("battery_level").is_some() || body.get("channel_utilization").is_some() {
+                Variant::DeviceMetrics(DeviceMetrics {
+                    battery_level: json_u32(&body, "battery_level"),
+                    voltage: json_f32(&body, "voltage"),
+                    channel_utilization: json_f32(&body, "channel_utilization"),
+                    air_util_tx: json_f32(&body, "air_util_tx"),
+                    uptime_seconds: json_u32(&body, "uptime_seconds"),
+                })
+            } else {
+                Variant::EnvironmentMetrics(EnvironmentMetrics {
+                    temperature: json_f32(&body, "temperature"),
+                    relative_humidity: json_f32(&body, "relative_humidity"),
+                    barometric_pressure: json_f32(&body, "barometric_pressure"),
+                    gas_resistance: json_f32(&body, "gas_resistance"),
+                    iaq: json_u32(&body, "iaq"),
+                    ..Default::default()
+                })
+            };
+            let telemetry = Telemetry {
+                time: json_u32(&msg, "timestamp"),
+                variant: Some(variant),
+            };
+            (PortNum::TelemetryApp, telemetry.encode_to_vec())
+        }
+        "neighborinfo" => {
+            let neighbors = body.get("neighbors").and_then(Value::as_array).cloned().unwrap_or_default();
+            let neighbor_info = NeighborInfo {
+                node_id: json_u32(&body, "node_id"),
+                node_broadcast_interval_secs: json_u32(&body, "node_broadcast_interval_secs"),
+                neighbors: neighbors.iter().map(|n| Neighbor {
+                    node_id: json_u32(n, "node_id"),
+                    snr: json_f32(n, "snr"),
+                    ..Default::default()
+                }).collect(),
+                ..Default::default()
+            };
+            (PortNum::NeighborinfoApp, neighbor_info.encode_to_vec())
+        }
+        _ => return Ok(None),
+    };
+    let hop_start = json_u32(&msg, "hop_start");
+    let packet = MeshPacket {
+        from: json_u32(&msg, "from"),
+        to: json_u32(&msg, "to"),
+        channel: json_u32(&msg, "channel"),
+        id: json_u32(&msg, "id"),
+        rx_time: json_u32(&msg, "timestamp"),
+        rx_snr: json_f32(&msg, "snr"),
+        rx_rssi: json_i32(&msg, "rssi"),
+        hop_start,
+        hop_limit: hop_start.saturating_sub(json_u32(&msg, "hops_away")),
+        payload_variant: Some(mp_variant::Decoded(Data {
+            portnum: portnum as i32,
+            payload: bytes,
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    Ok(Some(packet))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // main's #[macro_use] of tokio would otherwise turn #[test] into tokio::test
+    use std::prelude::v1::test;
+
+    // Data { portnum: TEXT_MESSAGE_APP, payload: "hello" } sent by !a1b2c3d4 as packet 0x1234abcd,
+    // encrypted with the default LongFast key
+    const LONGFAST_CIPHERTEXT: [u8; 9] = [0xb4, 0xf9, 0xd4, 0x32, 0x58, 0x1a, 0x77, 0xf9, 0x9a];
+    const PACKET_ID: u32 = 0x1234abcd;
+    const FROM: u32 = 0xa1b2c3d4;
+
+    fn longfast_keys() -> HashMap<String, Vec<u8>> {
+        let psk = BASE64.decode("AQ==").unwrap();
+        HashMap::from([("LongFast".to_string(), expand_psk(&psk).unwrap())])
+    }
+
+    #[test]
+    fn decrypts_longfast_packet() {
+        let plaintext = decrypt(&DEFAULT_KEY, PACKET_ID, FROM, &LONGFAST_CIPHERTEXT);
+        let data = Data::decode(plaintext.as_slice()).unwrap();
+        assert_eq!(data.portnum(), PortNum::TextMessageApp);
+        assert_eq!(data.payload, b"hello");
+    }
+
+    #[test]
+    fn decodes_encrypted_envelope() {
+        let envelope = ServiceEnvelope {
+            packet: Some(MeshPacket {
+                from: FROM,
+                id: PACKET_ID,
+                payload_variant: Some(mp_variant::Encrypted(LONGFAST_CIPHERTEXT.to_vec())),
+                ..Default::default()
+            }),
+            channel_id: "LongFast".to_string(),
+            gateway_id: "!a1b2c3d4".to_string(),
+        };
+        let topic = "msh/US/2/e/LongFast/!a1b2c3d4";
+        let packet = decode_publish(topic, &envelope.encode_to_vec(), &longfast_keys()).unwrap();
+        match packet.payload_variant {
+            Some(mp_variant::Decoded(data)) => assert_eq!(data.payload, b"hello"),
+            other => panic!("expected a decoded payload, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn skips_envelope_without_key() {
+        let envelope = ServiceEnvelope {
+            packet: Some(MeshPacket {
+                from: FROM,
+                id: PACKET_ID,
+                payload_variant: Some(mp_variant::Encrypted(LONGFAST_CIPHERTEXT.to_vec())),
+                ..Default::default()
+            }),
+            channel_id: "Private".to_string(),
+            gateway_id: "!a1b2c3d4".to_string(),
+        };
+        assert!(decode_envelope("msh/US/2/e/Private/!a1b2c3d4", &envelope.encode_to_vec(), &longfast_keys()).is_none());
+    }
+
+    #[test]
+    fn expands_one_byte_psk() {
+        // 0 means no encryption, 1 is the default key and each index after that bumps its last byte
+        assert_eq!(expand_psk(&[0]), None);
+        assert_eq!(expand_psk(&[1]).unwrap(), DEFAULT_KEY.to_vec());
+        let mut key = DEFAULT_KEY.to_vec();
+        key[15] = 0x02;
+        assert_eq!(expand_psk(&[2]).unwrap(), key);
+        key[15] = 0x0a;
+        assert_eq!(expand_psk(&[10]).unwrap(), key);
+    }
+
+    #[test]
+    fn pads_short_psks() {
+        assert_eq!(expand_psk(&[]), None);
+        assert_eq!(expand_psk(&[0xaa; 5]).unwrap(), [vec![0xaa; 5], vec![0; 11]].concat());
+        assert_eq!(expand_psk(&[0xaa; 20]).unwrap(), [vec![0xaa; 20], vec![0; 12]].concat());
+        assert_eq!(expand_psk(&[0xaa; 32]).unwrap(), vec![0xaa; 32]);
+    }
+
+    #[test]
+    fn classifies_topics() {
+        assert!(matches!(topic_kind("msh/US/2/e/LongFast/!a1b2c3d4"), Some(TopicKind::Protobuf)));
+        assert!(matches!(topic_kind("msh/EU_868/DE/2/c/LongFast/!a1b2c3d4"), Some(TopicKind::Protobuf)));
+        assert!(matches!(topic_kind("msh/US/2/json/LongFast/!a1b2c3d4"), Some(TopicKind::Json)));
+        assert!(topic_kind("msh/US/2/stat/!a1b2c3d4").is_none());
+        assert!(topic_kind("msh/US/2/map/").is_none());
+        assert!(topic_kind("msh/US/LongFast").is_none());
+    }
+
+    #[test]
+    fn decodes_json_uplink() {
+        let payload = br#"{"channel":0,"from":2712847316,"hop_start":3,"hops_away":1,"id":305441741,
+            "payload":{"altitude":42,"latitude_i":377749000,"longitude_i":-1224194000,"precision_bits":32,"sats_in_view":7,"time":1700000000},
+            "rssi":-97,"sender":"!a1b2c3d4","snr":5.25,"timestamp":1700000002,"to":4294967295,"type":"position"}"#;
+        let packet = decode_json(payload).unwrap().unwrap();
+        assert_eq!(packet.from, FROM);
+        assert_eq!(packet.id, PACKET_ID);
+        assert_eq!(packet.to, 0xffffffff);
+        assert_eq!(packet.rx_time, 1700000002);
+        assert_eq!(packet.rx_rssi, -97);
+        assert_eq!(packet.rx_snr, 5.25);
+        assert_eq!((packet.hop_start, packet.hop_limit), (3, 2));
+        let Some(mp_variant::Decoded(data)) = packet.payload_variant else { panic!("json packets are always decoded") };
+        assert_eq!(data.portnum(), PortNum::PositionApp);
+        let position = Position::decode(data.payload.as_slice()).unwrap();
+        assert_eq!(position.latitude_i, 377749000);
+        assert_eq!(position.longitude_i, -1224194000);
+        assert_eq!(position.altitude, 42);
+        assert_eq!(position.sats_in_view, 7);
+    }
+
+    #[test]
+    fn ignores_unknown_json_types() {
+        let payload = br#"{"from":2712847316,"id":1,"payload":{},"type":"sendtext"}"#;
+        assert!(decode_json(payload).unwrap().is_none());
+        assert!(decode_json(b"not json").is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::{BTreeMap, HashMap};
+use lazy_static::lazy_static;
+use meshtastic::protobufs::{Position, User};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use crate::consts::GPS_PRECISION_FACTOR;
+use crate::store;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Node {
+    pub id: String,
+    pub short_name: Option<String>,
+    pub long_name: Option<String>,
+    pub hw_model: Option<String>,
+    pub role: Option<String>,
+    pub is_licensed: Option<bool>,
+    pub last_heard: Option<u64>,
+    pub snr: Option<f32>,
+    pub hops_away: Option<u32>,
+    pub packets: u64,
+    pub position: Option<NodePosition>,
+    // latest reading of each telemetry value, keyed by its metric name
+    pub telemetry: BTreeMap<String, f64>,
+    pub telemetry_time: Option<u64>,
+    // neighbor id -> snr the node last reported hearing it at
+    pub neighbors: BTreeMap<String, f32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodePosition {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: i32,
+    pub precision_bits: u32,
+    pub sats_in_view: u32,
+    pub time: u64,
+}
+
+lazy_static! {
+    static ref NODES: RwLock<HashMap<String, Node>> = RwLock::new(HashMap::new());
+}
+
+async fn update<F: FnOnce(&mut Node)>(device_id: &str, f: F) {
+    let mut nodes = NODES.write().await;
+    let node = nodes.entry(device_id.to_string()).or_insert_with(|| {
+        store::load_node(device_id).unwrap_or_else(|| Node {
+            id: device_id.to_string(),
+            ..Default::default()
+        })
+    });
+    f(node);
+    store::save(node);
+}
+
+// puts back a node loaded from the store, without writing it out again
+pub async fn restore(node: Node) {
+    NODES.write().await.insert(node.id.clone(), node);
+}
+
+pub async fn count_packet(device_id: &str) {
+    update(device_id, |node| node.packets += 1).await;
+}
+
+pub async fn heard(device_id: &str, at: u64) {
+    update(device_id, |node| node.last_heard = Some(at)).await;
+}
+
+pub async fn update_user(device_id: &str, user: &User) {
+    update(device_id, |node| {
+        node.short_name = Some(user.short_name.clone());
+        node.long_name = Some(user.long_name.clone());
+        node.hw_model = Some(user.hw_model().as_str_name().to_string());
+        node.role = Some(user.role().as_str_name().to_string());
+        node.is_licensed = Some(user.is_licensed);
+    }).await;
+}
+
+pub async fn update_radio(device_id: &str, snr: f32, hops_away: u32) {
+    update(device_id, |node| {
+        node.snr = Some(snr);
+        node.hops_away = Some(hops_away);
+    }).await;
+}
+
+pub async fn update_position(device_id: &str, position: &Position) {
+    // no fix, or location sharing off
+    if position.latitude_i == 0 && position.longitude_i == 0 {
+        return;
+    }
+    let time = match position.time {
+        0 => crate::get_secs(),
+        t => t as u64
+    };
+    update(device_id, |node| {
+        node.position = Some(NodePosition {
+            latitude: position.latitude_i as f64 * GPS_PRECISION_FACTOR,
+            longitude: position.longitude_i as f64 * GPS_PRECISION_FACTOR,
+            altitude: position.altitude,
+            precision_bits: position.precision_bits,
+            sats_in_view: position.sats_in_view,
+            time,
+        });
+    }).await;
+}
+
+pub async fn update_telemetry(device_id: &str, readings: &[(String, f64)], time: u64) {
+    update(device_id, |node| {
+        for (name, value) in readings.iter() {
+            node.telemetry.insert(name.clone(), *value);
+        }
+        node.telemetry_time = Some(time);
+    }).await;
+}
+
+pub async fn update_neighbors(device_id: &str, neighbors: BTreeMap<String, f32>) {
+    update(device_id, |node| node.neighbors = neighbors).await;
+}
+
+// only forgets it here, the store keeps it until its retention runs out
+pub async fn remove(device_id: &str) {
+    let node = NODES.write().await.remove(device_id);
+    if let Some(node) = node {
+        store::set_dormant(node);
+    }
+}
+
+pub async fn get(device_id: &str) -> Option<Node> {
+    NODES.read().await.get(device_id).cloned()
+}
+
+pub async fn all() -> Vec<Node> {
+    let mut nodes: Vec<Node> = NODES.read().await.values().cloned().collect();
+    nodes.sort_by(|a, b| a.id.cmp(&b.id));
+    nodes
+}
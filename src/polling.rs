@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use lazy_static::lazy_static;
+use meshtastic::protobufs::{telemetry, DeviceMetrics, PortNum, Position, Telemetry};
+use meshtastic::Message;
+use metrics::counter;
+use strum::Display;
+use crate::structs::{IPCMessage, PollingConfig, RadioState};
+use crate::{app_metrics, consts, node_db, RADIO_STATE};
+use crate::meshtastic_interaction::{parse_node_id, request_packet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum PollKind {
+    Telemetry,
+    Position,
+}
+
+struct Request {
+    target: u32,
+    kind: PollKind,
+    sent_at: Instant,
+}
+
+#[derive(Default)]
+struct Poller {
+    // packet id of the request -> the request waiting on a response
+    pending: HashMap<u32, Request>,
+    last_received: HashMap<(u32, PollKind), u64>,
+    last_polled: HashMap<(u32, PollKind), u64>,
+    last_sent: u64,
+}
+
+lazy_static! {
+    static ref POLLER: Mutex<Poller> = Mutex::new(Poller::default());
+}
+
+fn record(metric: &'static str, target: u32, kind: PollKind) {
+    let labels = vec![
+        (consts::LABEL_DEVICE_ID, format!("!{:x}", target)),
+        (consts::LABEL_KIND, kind.to_string()),
+    ];
+    counter!(metric, &labels).increment(1);
+}
+
+// Called every housekeeping tick.  The budget is requests per hour across every node, spent
+// evenly, so a long list of stale nodes gets worked through rather than sent in one burst.
+pub async fn next_request(config: &PollingConfig) -> Option<IPCMessage> {
+    if !config.enabled || config.budget == 0 {
+        return None;
+    }
+    expire_requests(config.timeout);
+    if *RADIO_STATE.read().await != RadioState::Connected {
+        return None;
+    }
+
+    let now = crate::get_secs();
+    // until a node sends us something itself, what the node table already has (restored from
+    // the store, or the radio's node db) counts as received, so a restart doesn't poll them all
+    let mut known: HashMap<(u32, PollKind), u64> = HashMap::new();
+    for node in config.nodes.iter() {
+        let Some(id) = parse_node_id(&node.device_id) else { continue };
+        let Some(stored) = node_db::get(&format!("!{:x}", id)).await else { continue };
+        if let Some(time) = stored.telemetry_time {
+            known.insert((id, PollKind::Telemetry), time.min(now));
+        }
+        if let Some(position) = stored.position {
+            known.insert((id, PollKind::Position), position.time.min(now));
+        }
+    }
+    let mut poller = POLLER.lock().unwrap();
+    if now.saturating_sub(poller.last_sent) < 3600 / config.budget as u64 {
+        return None;
+    }
+    let wanted = config.nodes.iter()
+        .filter_map(|node| parse_node_id(&node.device_id).map(|id| (id, node)))
+        .flat_map(|(id, node)| [
+            (id, PollKind::Telemetry, node.telemetry_max_age),
+            (id, PollKind::Position, node.position_max_age),
+        ])
+        .filter_map(|(id, kind, max_age)| max_age.map(|age| (id, kind, age)));
+    // a node that never answers is only asked again once max_age has passed since the last try
+    let (target, kind) = wanted
+        .map(|(id, kind, max_age)| {
+            let received = poller.last_received.get(&(id, kind)).or(known.get(&(id, kind))).copied().unwrap_or(0);
+            let polled = poller.last_polled.get(&(id, kind)).copied().unwrap_or(0);
+            (id, kind, max_age, received.max(polled))
+        })
+        .filter(|(_, _, max_age, last)| now.saturating_sub(*last) >= *max_age)
+        .min_by_key(|(_, _, _, last)| *last)
+        .map(|(id, kind, _, _)| (id, kind))?;
+
+    let (portnum, payload) = match kind {
+        PollKind::Telemetry => (PortNum::TelemetryApp, Telemetry {
+            variant: Some(telemetry::Variant::DeviceMetrics(DeviceMetrics::default())),
+            ..Default::default()
+        }.encode_to_vec()),
+        PollKind::Position => (PortNum::PositionApp, Position::default().encode_to_vec()),
+    };
+    let (id, request) = request_packet(target, portnum, payload, config.hop_limit);
+    poller.pending.insert(id, Request { target, kind, sent_at: Instant::now() });
+    poller.last_polled.insert((target, kind), now);
+    poller.last_sent = now;
+    drop(poller);
+
+    info!("Polling !{:x} for {kind}", target);
+    record(app_metrics::METRIC_POLL_REQUESTS, target, kind);
+    Some(request)
+}
+
+fn expire_requests(timeout: u64) {
+    let mut poller = POLLER.lock().unwrap();
+    let expired: Vec<u32> = poller.pending.iter()
+        .filter(|(_, r)| r.sent_at.elapsed().as_secs() >= timeout)
+        .map(|(id, _)| *id)
+        .collect();
+    for id in expired {
+        if let Some(request) = poller.pending.remove(&id) {
+            warn!("{} poll of !{:x} timed out after {timeout} seconds", request.kind, request.target);
+            record(app_metrics::METRIC_POLL_TIMEOUTS, request.target, request.kind);
+        }
+    }
+}
+
+// Any telemetry or position counts as fresh data, whether or not we asked for it.
+pub fn received(from: u32, request_id: u32, kind: PollKind) {
+    let mut poller = POLLER.lock().unwrap();
+    poller.last_received.insert((from, kind), crate::get_secs());
+    if request_id == 0 {
+        return;
+    }
+    if let Some(request) = poller.pending.remove(&request_id) {
+        record(app_metrics::METRIC_POLL_RESPONSES, request.target, request.kind);
+    }
+}
@@ -1,11 +1,52 @@
-use meshtastic::protobufs::{DeviceMetadata, MeshPacket, MyNodeInfo, NodeInfo, PortNum, Position, Telemetry, User};
+use meshtastic::protobufs::{DeviceMetadata, MeshPacket, MyNodeInfo, NeighborInfo, NodeInfo, PortNum, Position, RouteDiscovery, Routing, Telemetry, User};
+use meshtastic::protobufs::routing;
 use meshtastic::protobufs::mesh_packet::PayloadVariant as mp_variant;
-use metrics::{counter, gauge};
+use metrics::{counter, gauge, histogram};
 use meshtastic::protobufs::telemetry::Variant;
 use meshtastic::Message;
 use std::ops::Mul;
-use crate::{consts, SETTINGS, app_metrics};
+use crate::{consts, SETTINGS, app_metrics, lifecycle, message_log, node_db, polling, series, store, traceroute};
+use crate::polling::PollKind;
 use crate::consts::{GPS_PRECISION_FACTOR, LABEL_SENSOR_CHANNEL};
+use meshtastic::protobufs::Data;
+use thiserror::Error;
+use std::collections::BTreeMap;
+use crate::node_db::Node;
+
+#[derive(Debug, Error)]
+pub enum ProcessingError {
+    #[error("packet payload is still encrypted")]
+    NotDecoded,
+    #[error("couldn't decode payload: {0}")]
+    Decode(String),
+    #[error("payload has no variant we recognize")]
+    MissingVariant,
+    #[error("position can't be geohashed: {0}")]
+    InvalidPosition(String),
+}
+
+impl ProcessingError {
+    // kept short and fixed, it's used as a metric label
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ProcessingError::NotDecoded => "not_decoded",
+            ProcessingError::Decode(_) => "decode",
+            ProcessingError::MissingVariant => "missing_variant",
+            ProcessingError::InvalidPosition(_) => "invalid_position",
+        }
+    }
+}
+
+fn decoded_content(packet: &MeshPacket) -> Result<Data, ProcessingError> {
+    match &packet.payload_variant {
+        Some(mp_variant::Decoded(content)) => Ok(content.clone()),
+        _ => Err(ProcessingError::NotDecoded),
+    }
+}
+
+fn decode_payload<M: Message + Default>(content: &Data) -> Result<M, ProcessingError> {
+    M::decode(content.payload.as_slice()).map_err(|e| ProcessingError::Decode(e.to_string()))
+}
 
 pub async fn process_my_info(packet: &MyNodeInfo) {
     info!("Connected to node !{:x}",packet.my_node_num);
@@ -13,6 +54,7 @@ pub async fn process_my_info(packet: &MyNodeInfo) {
         let mut config = SETTINGS.write().await;
         config.___node_id = packet.my_node_num;
     }
+    lifecycle::heard(&format!("!{:x}", packet.my_node_num), crate::get_secs()).await;
 }
 
 pub async fn process_metadata(metadata: &DeviceMetadata) {
@@ -37,65 +79,214 @@ pub async fn process_node_info(node_info: &NodeInfo) {
         (consts::LABEL_DEVICE_ID, device_id.clone())
     ];
     info!("Received cached NodeInfo for {device_id}");
+    // a NodeDB entry that was never heard still gets series, so start its ttl from now or they'd never expire
+    let last_heard = match node_info.last_heard {
+        0 => crate::get_secs(),
+        t => t as u64,
+    };
+    lifecycle::heard(&device_id, last_heard).await;
     gauge!(app_metrics::METRIC_SNR, &labels).set(node_info.snr);
     gauge!(app_metrics::METRIC_HOPS_AWAY, &labels).set(node_info.hops_away);
-    counter!(app_metrics::METRIC_RX_MSG_COUNT, &labels).increment(1);
-    gauge!(app_metrics::METRIC_LAST_HEARD_SECS, &labels).set(node_info.last_heard);
+    node_db::update_radio(&device_id, node_info.snr, node_info.hops_away).await;
+    if let Some(user) = &node_info.user {
+        node_db::update_user(&device_id, user).await;
+    }
+    if let Some(position) = &node_info.position {
+        node_db::update_position(&device_id, position).await;
+    }
     if let Some(dm) = &node_info.device_metrics {
         gauge!(app_metrics::METRIC_CHAN_UTIL, &labels).set(dm.channel_utilization);
         gauge!(app_metrics::METRIC_AIR_UTIL, &labels).set(dm.air_util_tx);
         gauge!(app_metrics::METRIC_BATTERY, &labels).set(dm.battery_level);
         gauge!(app_metrics::METRIC_VOLTAGE, &labels).set(dm.voltage);
         gauge!(app_metrics::METRIC_UPTIME, &labels).set(dm.uptime_seconds);
+        let readings = vec![
+            (app_metrics::METRIC_CHAN_UTIL.to_string(), dm.channel_utilization as f64),
+            (app_metrics::METRIC_AIR_UTIL.to_string(), dm.air_util_tx as f64),
+            (app_metrics::METRIC_BATTERY.to_string(), dm.battery_level as f64),
+            (app_metrics::METRIC_VOLTAGE.to_string(), dm.voltage as f64),
+            (app_metrics::METRIC_UPTIME.to_string(), dm.uptime_seconds as f64),
+        ];
+        let time = match node_info.last_heard {
+            0 => crate::get_secs(),
+            t => t as u64
+        };
+        node_db::update_telemetry(&device_id, &readings, time).await;
     }
 }
 
+// re-seeds the gauges for a node loaded from the store at startup
+pub async fn process_stored_node(node: &Node) {
+    let labels = vec![
+        (consts::LABEL_DEVICE_ID, node.id.clone())
+    ];
+    if let Some(last_heard) = node.last_heard {
+        lifecycle::heard(&node.id, last_heard).await;
+    }
+    if let (Some(short_name), Some(long_name), Some(hw_model), Some(role), Some(is_licensed)) =
+        (&node.short_name, &node.long_name, &node.hw_model, &node.role, node.is_licensed) {
+        let info_labels = vec![
+            (consts::LABEL_DEVICE_ID, node.id.clone()),
+            (consts::LABEL_HW_MODEL, hw_model.clone()),
+            (consts::LABEL_DEVICE_ROLE, role.clone()),
+            (consts::LABEL_LICENSED, is_licensed.to_string()),
+            (consts::LABEL_SHORT_NAME, short_name.clone()),
+            (consts::LABEL_LONG_NAME, long_name.clone()),
+        ];
+        gauge!(app_metrics::METRIC_DEVICE_INFO, &info_labels).set(node.last_heard.unwrap_or(0) as f64);
+    }
+    if let Some(snr) = node.snr {
+        gauge!(app_metrics::METRIC_SNR, &labels).set(snr);
+    }
+    if let Some(hops_away) = node.hops_away {
+        gauge!(app_metrics::METRIC_HOPS_AWAY, &labels).set(hops_away);
+    }
+    if let Some(position) = &node.position {
+        gauge!(app_metrics::METRIC_POS_LATITUDE, &labels).set(position.latitude);
+        gauge!(app_metrics::METRIC_POS_LONGITUDE, &labels).set(position.longitude);
+        gauge!(app_metrics::METRIC_POS_ALTITUDE, &labels).set(position.altitude);
+        gauge!(app_metrics::METRIC_POS_SATS_IN_VIEW, &labels).set(position.sats_in_view);
+        gauge!(app_metrics::METRIC_POS_PRECISION_BITS, &labels).set(position.precision_bits);
+    }
+    for (name, value) in node.telemetry.iter() {
+        // power channel readings are stored as e.g. voltage_ch1
+        let mut reading_labels = labels.clone();
+        let metric = match name.rsplit_once('_') {
+            Some((metric, channel)) if channel.starts_with("ch") && channel[2..].parse::<u8>().is_ok() => {
+                reading_labels.push((LABEL_SENSOR_CHANNEL, channel.to_string()));
+                metric.to_string()
+            }
+            _ => name.clone(),
+        };
+        gauge!(metric, &reading_labels).set(*value);
+    }
+    counter!(app_metrics::METRIC_RX_MSG_COUNT, &labels).absolute(node.packets);
+}
+
 pub async fn process_mesh_packet(mesh_packet: &MeshPacket) {
     if let Some(variant) = mesh_packet.payload_variant.clone() {
         if let mp_variant::Decoded(content) = variant {
-            match content.portnum() {
+            let result = match content.portnum() {
                 PortNum::PositionApp => process_position_app(&mesh_packet).await,
                 PortNum::NodeinfoApp => process_nodeinfo_app(&mesh_packet).await,
                 PortNum::TelemetryApp => process_telemetry_app(&mesh_packet).await,
-                PortNum::NeighborinfoApp => {}
+                PortNum::NeighborinfoApp => process_neighborinfo_app(&mesh_packet).await,
+                PortNum::TextMessageApp => process_text_message_app(&mesh_packet).await,
+                PortNum::RoutingApp => process_routing_app(&mesh_packet).await,
+                PortNum::TracerouteApp => process_traceroute_app(&mesh_packet).await,
                 _ => {
                     info!("Received a payload type of {} but we don't consume its data.",content.portnum().as_str_name());
+                    Ok(())
                 }
+            };
+            if let Err(e) = result {
+                warn!("Couldn't process {} packet from !{:x}: {e}", content.portnum().as_str_name(), mesh_packet.from);
+                let labels = vec![
+                    (consts::LABEL_PORTNUM, content.portnum().as_str_name().to_string()),
+                    (consts::LABEL_REASON, e.reason().to_string()),
+                ];
+                counter!(app_metrics::METRIC_DECODE_ERRORS, &labels).increment(1);
             }
             let device_id = match content.source {
                 0 => { format!("!{:x}", mesh_packet.from) }
                 _ => { format!("!{:x}", content.source) }
             };
+            let heard_at = match mesh_packet.rx_time {
+                0 => crate::get_secs(),
+                t => t as u64
+            };
+            lifecycle::heard(&device_id, heard_at).await;
             let labels = vec![
                 (consts::LABEL_DEVICE_ID, device_id),
             ];
             counter!(app_metrics::METRIC_RX_MSG_COUNT, &labels).increment(1);
+            node_db::count_packet(&labels[0].1).await;
+            // firmware older than 2.3 doesn't fill in hop_start, so we can't tell
+            if mesh_packet.hop_start > 0 && mesh_packet.hop_limit <= mesh_packet.hop_start {
+                let hops = mesh_packet.hop_start - mesh_packet.hop_limit;
+                gauge!(app_metrics::METRIC_HOPS_AWAY, &labels).set(hops);
+                histogram!(app_metrics::METRIC_HOPS_HISTOGRAM, &labels).record(hops);
+            }
+            if received_directly(mesh_packet) {
+                gauge!(app_metrics::METRIC_RSSI, &labels).set(mesh_packet.rx_rssi);
+                gauge!(app_metrics::METRIC_SNR, &labels).set(mesh_packet.rx_snr);
+                histogram!(app_metrics::METRIC_RSSI_HISTOGRAM, &labels).record(mesh_packet.rx_rssi);
+                histogram!(app_metrics::METRIC_SNR_HISTOGRAM, &labels).record(mesh_packet.rx_snr);
+            }
         }
     }
 }
 
-pub async fn process_position_app(packet: &MeshPacket) {
-    let mp_variant::Decoded(content) = packet.clone().payload_variant.unwrap() else { unreachable!() };
-    let data = Position::decode(content.payload.as_slice()).unwrap();
+// rx_rssi/rx_snr describe the last hop only, so they're only meaningful for the sender when
+// the packet wasn't relayed.  Packets from our own node or via mqtt have no rf stats at all.
+fn received_directly(mesh_packet: &MeshPacket) -> bool {
+    mesh_packet.rx_rssi != 0
+        && !mesh_packet.via_mqtt
+        && mesh_packet.hop_start > 0
+        && mesh_packet.hop_start == mesh_packet.hop_limit
+}
+
+pub async fn process_position_app(packet: &MeshPacket) -> Result<(), ProcessingError> {
+    let content = decoded_content(packet)?;
+    let data: Position = decode_payload(&content)?;
+    polling::received(packet.from, content.request_id, PollKind::Position);
+    let geohash_precision = SETTINGS.read().await.geohash_precision;
+    let latitude = GPS_PRECISION_FACTOR.mul(data.latitude_i as f64);
+    let longitude = GPS_PRECISION_FACTOR.mul(data.longitude_i as f64);
     let coord = geohash::Coord {
-        x: GPS_PRECISION_FACTOR.mul(data.longitude_i as f32) as f64,
-        y: GPS_PRECISION_FACTOR.mul(data.latitude_i as f32) as f64,
+        x: longitude,
+        y: latitude,
     };
     let device_id = match content.source {
         0 => { format!("!{:x}", packet.from) }
         _ => { format!("!{:x}", content.source) }
     };
+    // a position without a fix (or with location sharing off) comes through as 0,0
+    let has_fix = data.latitude_i != 0 || data.longitude_i != 0;
+    let geohash = match has_fix {
+        true => Some(geohash::encode(coord, geohash_precision).map_err(|e| ProcessingError::InvalidPosition(e.to_string()))?),
+        false => None,
+    };
     let labels = vec![
         (consts::LABEL_DEVICE_ID, device_id.clone()),
-        (consts::LABEL_GEOHASH, geohash::encode(coord, 10).unwrap()),
     ];
     info!("Updating position data for {device_id}");
     gauge!(app_metrics::METRIC_POS_SATS_IN_VIEW, &labels).set(data.sats_in_view);
+
+    if let Some(geohash) = geohash {
+        // one geohash series per node, the one for wherever it was before is left to expire
+        let moved_from = series::retire(|key| {
+            key.name() == app_metrics::METRIC_POS_GEOHASH_INFO
+                && series::label_value(key, consts::LABEL_DEVICE_ID) == Some(device_id.as_str())
+                && series::label_value(key, consts::LABEL_GEOHASH) != Some(geohash.as_str())
+        });
+        if moved_from > 0 {
+            debug!("{device_id} moved, retired its previous geohash");
+        }
+        let mut geohash_labels = labels.clone();
+        geohash_labels.push((consts::LABEL_GEOHASH, geohash));
+        gauge!(app_metrics::METRIC_POS_GEOHASH_INFO, &geohash_labels).set(crate::get_secs() as f64);
+        gauge!(app_metrics::METRIC_POS_LATITUDE, &labels).set(latitude);
+        gauge!(app_metrics::METRIC_POS_LONGITUDE, &labels).set(longitude);
+        gauge!(app_metrics::METRIC_POS_ALTITUDE, &labels).set(data.altitude);
+    }
+    gauge!(app_metrics::METRIC_POS_GROUND_SPEED, &labels).set(data.ground_speed);
+    gauge!(app_metrics::METRIC_POS_GROUND_TRACK, &labels).set(consts::GROUND_TRACK_FACTOR.mul(data.ground_track as f64));
+    gauge!(app_metrics::METRIC_POS_PDOP, &labels).set(consts::DOP_FACTOR.mul(data.pdop as f64));
+    gauge!(app_metrics::METRIC_POS_HDOP, &labels).set(consts::DOP_FACTOR.mul(data.hdop as f64));
+    gauge!(app_metrics::METRIC_POS_PRECISION_BITS, &labels).set(data.precision_bits);
+    gauge!(app_metrics::METRIC_POS_FIX_QUALITY, &labels).set(data.fix_quality);
+    gauge!(app_metrics::METRIC_POS_FIX_TYPE, &labels).set(data.fix_type);
+    if data.time > 0 {
+        gauge!(app_metrics::METRIC_POS_TIMESTAMP, &labels).set(data.time);
+    }
+    node_db::update_position(&device_id, &data).await;
+    Ok(())
 }
 
-pub async fn process_nodeinfo_app(packet: &MeshPacket) {
-    let mp_variant::Decoded(content) = packet.clone().payload_variant.unwrap() else { unreachable!() };
-    let data = User::decode(content.payload.as_slice()).unwrap();
+pub async fn process_nodeinfo_app(packet: &MeshPacket) -> Result<(), ProcessingError> {
+    let content = decoded_content(packet)?;
+    let data: User = decode_payload(&content)?;
     let labels = vec![
         (consts::LABEL_DEVICE_ID, data.clone().id),
         (consts::LABEL_HW_MODEL, data.clone().hw_model().as_str_name().to_string()),
@@ -107,70 +298,334 @@ pub async fn process_nodeinfo_app(packet: &MeshPacket) {
 
     info!("Received updated NodeInfo data for {}",data.clone().id);
     gauge!(app_metrics::METRIC_DEVICE_INFO, &labels).set(crate::get_secs() as f64);
+    node_db::update_user(&data.id, &data).await;
+    Ok(())
+}
+
+pub async fn process_neighborinfo_app(packet: &MeshPacket) -> Result<(), ProcessingError> {
+    let content = decoded_content(packet)?;
+    let data: NeighborInfo = decode_payload(&content)?;
+    let device_id = match data.node_id {
+        0 => { format!("!{:x}", packet.from) }
+        _ => { format!("!{:x}", data.node_id) }
+    };
+    let labels = vec![
+        (consts::LABEL_DEVICE_ID, device_id.clone())
+    ];
+    info!("Processing NeighborInfo from {device_id} with {} neighbors", data.neighbors.len());
+    gauge!(app_metrics::METRIC_NEIGHBOR_BROADCAST_INTERVAL, &labels).set(data.node_broadcast_interval_secs);
+    let now = crate::get_secs();
+    let mut neighbors = BTreeMap::new();
+    for neighbor in data.neighbors.iter() {
+        let mut edge_labels = labels.clone();
+        edge_labels.push((consts::LABEL_NEIGHBOR_ID, format!("!{:x}", neighbor.node_id)));
+        gauge!(app_metrics::METRIC_NEIGHBOR_SNR, &edge_labels).set(neighbor.snr);
+        let last_seen = match neighbor.last_rx_time {
+            0 => now,
+            t => t as u64
+        };
+        gauge!(app_metrics::METRIC_NEIGHBOR_LAST_SEEN, &edge_labels).set(last_seen as f64);
+        neighbors.insert(format!("!{:x}", neighbor.node_id), neighbor.snr);
+    }
+    // each report is the node's full list, so an edge missing from it is a link that's gone
+    let dropped = series::retire(|key| {
+        (key.name() == app_metrics::METRIC_NEIGHBOR_SNR || key.name() == app_metrics::METRIC_NEIGHBOR_LAST_SEEN)
+            && series::label_value(key, consts::LABEL_DEVICE_ID) == Some(device_id.as_str())
+            && series::label_value(key, consts::LABEL_NEIGHBOR_ID).is_some_and(|id| !neighbors.contains_key(id))
+    });
+    if dropped > 0 {
+        debug!("{device_id} no longer reports some neighbors, retired {dropped} edge series");
+    }
+    node_db::update_neighbors(&device_id, neighbors).await;
+    Ok(())
 }
 
-pub async fn process_telemetry_app(packet: &MeshPacket) {
-    let mp_variant::Decoded(content) = packet.clone().payload_variant.unwrap() else { unreachable!() };
-    let data = Telemetry::decode(content.payload.as_slice()).unwrap();
+pub async fn process_text_message_app(packet: &MeshPacket) -> Result<(), ProcessingError> {
+    let content = decoded_content(packet)?;
+    let text = String::from_utf8_lossy(content.payload.as_slice());
     let device_id = match content.source {
         0 => { format!("!{:x}", packet.from) }
         _ => { format!("!{:x}", content.source) }
     };
-    let mut labels = vec![
+    let to = match packet.to {
+        consts::BROADCAST_ADDR => { "^all".to_string() }
+        _ => { format!("!{:x}", packet.to) }
+    };
+    let labels = vec![
+        (consts::LABEL_DEVICE_ID, device_id.clone()),
+        (consts::LABEL_CHANNEL, packet.channel.to_string()),
+    ];
+    let channel_labels = vec![
+        (consts::LABEL_CHANNEL, packet.channel.to_string()),
+    ];
+    info!("Received text message from {device_id} on channel {}", packet.channel);
+    counter!(app_metrics::METRIC_TEXT_MESSAGES, &labels).increment(1);
+    histogram!(app_metrics::METRIC_TEXT_MESSAGE_LENGTH, &channel_labels).record(content.payload.len() as f64);
+    let rx_time = match packet.rx_time {
+        0 => crate::get_secs(),
+        t => t as u64
+    };
+    message_log::append(&device_id, &to, packet.channel, &text, rx_time);
+    Ok(())
+}
+
+pub async fn process_routing_app(packet: &MeshPacket) -> Result<(), ProcessingError> {
+    let content = decoded_content(packet)?;
+    let data: Routing = decode_payload(&content)?;
+    let device_id = format!("!{:x}", packet.from);
+    let labels = vec![
+        (consts::LABEL_DEVICE_ID, device_id.clone())
+    ];
+    match data.variant {
+        Some(routing::Variant::ErrorReason(code)) => {
+            match routing::Error::try_from(code) {
+                Ok(routing::Error::None) => {
+                    counter!(app_metrics::METRIC_ROUTING_ACKS, &labels).increment(1);
+                }
+                Ok(reason) => {
+                    warn!("Routing error {} from {device_id} for packet {}", reason.as_str_name(), content.request_id);
+                    let mut error_labels = labels.clone();
+                    error_labels.push((consts::LABEL_REASON, reason.as_str_name().to_lowercase()));
+                    counter!(app_metrics::METRIC_ROUTING_ERRORS, &error_labels).increment(1);
+                    traceroute::failed(content.request_id, &reason.as_str_name().to_lowercase());
+                }
+                Err(_) => {
+                    warn!("Unknown routing error {code} from {device_id}");
+                    let mut error_labels = labels.clone();
+                    error_labels.push((consts::LABEL_REASON, "unknown".to_string()));
+                    counter!(app_metrics::METRIC_ROUTING_ERRORS, &error_labels).increment(1);
+                }
+            }
+        }
+        Some(_) => {
+            debug!("Route discovery from {device_id} arrived as a routing packet, ignoring.");
+        }
+        None => return Err(ProcessingError::MissingVariant),
+    }
+    Ok(())
+}
+
+pub async fn process_traceroute_app(packet: &MeshPacket) -> Result<(), ProcessingError> {
+    let content = decoded_content(packet)?;
+    // requests from other nodes come through here too, only replies carry a request_id
+    if content.request_id == 0 {
+        debug!("Traceroute request from !{:x} isn't a reply, ignoring.", packet.from);
+        return Ok(());
+    }
+    let data: RouteDiscovery = decode_payload(&content)?;
+    traceroute::reply(content.request_id, &data).await;
+    Ok(())
+}
+
+pub async fn process_telemetry_app(packet: &MeshPacket) -> Result<(), ProcessingError> {
+    let content = decoded_content(packet)?;
+    let data: Telemetry = decode_payload(&content)?;
+    polling::received(packet.from, content.request_id, PollKind::Telemetry);
+    let device_id = match content.source {
+        0 => { format!("!{:x}", packet.from) }
+        _ => { format!("!{:x}", content.source) }
+    };
+    let labels = vec![
         (consts::LABEL_DEVICE_ID, device_id.clone())
     ];
-    match data.variant.unwrap() {
+    // (metric, sensor channel, value)
+    let readings: Vec<(&str, Option<&str>, f64)> = match data.variant.ok_or(ProcessingError::MissingVariant)? {
         Variant::DeviceMetrics(dm) => {
             info!("Processing DeviceMetrics telemetry for {device_id}");
-            gauge!(app_metrics::METRIC_CHAN_UTIL, &labels).set(dm.channel_utilization);
-            gauge!(app_metrics::METRIC_AIR_UTIL, &labels).set(dm.air_util_tx);
-            gauge!(app_metrics::METRIC_BATTERY, &labels).set(dm.battery_level);
-            gauge!(app_metrics::METRIC_VOLTAGE, &labels).set(dm.voltage);
-            gauge!(app_metrics::METRIC_UPTIME, &labels).set(dm.uptime_seconds);
+            vec![
+                (app_metrics::METRIC_CHAN_UTIL, None, dm.channel_utilization as f64),
+                (app_metrics::METRIC_AIR_UTIL, None, dm.air_util_tx as f64),
+                (app_metrics::METRIC_BATTERY, None, dm.battery_level as f64),
+                (app_metrics::METRIC_VOLTAGE, None, dm.voltage as f64),
+                (app_metrics::METRIC_UPTIME, None, dm.uptime_seconds as f64),
+            ]
         }
         Variant::EnvironmentMetrics(em) => {
             info!("Processing EnvironmentMetrics telemetry for {device_id}");
-            gauge!(app_metrics::METRIC_TEMPERATURE, &labels).set(em.temperature);
-            gauge!(app_metrics::METRIC_HUMIDITY, &labels).set(em.relative_humidity);
-            gauge!(app_metrics::METRIC_BAROMETRIC_PRESSURE, &labels).set(em.barometric_pressure);
-            gauge!(app_metrics::METRIC_IAQ, &labels).set(em.iaq);
-            gauge!(app_metrics::METRIC_GAS_RESISTANCE, &labels).set(em.gas_resistance);
+            vec![
+                (app_metrics::METRIC_TEMPERATURE, None, em.temperature as f64),
+                (app_metrics::METRIC_HUMIDITY, None, em.relative_humidity as f64),
+                (app_metrics::METRIC_BAROMETRIC_PRESSURE, None, em.barometric_pressure as f64),
+                (app_metrics::METRIC_IAQ, None, em.iaq as f64),
+                (app_metrics::METRIC_GAS_RESISTANCE, None, em.gas_resistance as f64),
+            ]
         }
         Variant::AirQualityMetrics(aq) => {
             info!("Processing AirQualityMetrics telemetry for {device_id}");
-            gauge!(app_metrics::METRIC_PARTICLES_03UM, &labels).set(aq.particles_03um);
-            gauge!(app_metrics::METRIC_PARTICLES_05UM, &labels).set(aq.particles_05um);
-            gauge!(app_metrics::METRIC_PARTICLES_10UM, &labels).set(aq.particles_10um);
-            gauge!(app_metrics::METRIC_PARTICLES_25UM, &labels).set(aq.particles_25um);
-            gauge!(app_metrics::METRIC_PARTICLES_50UM, &labels).set(aq.particles_50um);
-            gauge!(app_metrics::METRIC_PARTICLES_100UM, &labels).set(aq.particles_100um);
-            gauge!(app_metrics::METRIC_PM10_STANDARD, &labels).set(aq.pm10_standard);
-            gauge!(app_metrics::METRIC_PM25_STANDARD, &labels).set(aq.pm25_standard);
-            gauge!(app_metrics::METRIC_PM100_STANDARD, &labels).set(aq.pm100_standard);
-            gauge!(app_metrics::METRIC_PM10_ENVIRONMENTAL, &labels).set(aq.pm10_environmental);
-            gauge!(app_metrics::METRIC_PM25_ENVIRONMENTAL, &labels).set(aq.pm25_environmental);
-            gauge!(app_metrics::METRIC_PM100_ENVIRONMENTAL, &labels).set(aq.pm100_environmental);
+            vec![
+                (app_metrics::METRIC_PARTICLES_03UM, None, aq.particles_03um as f64),
+                (app_metrics::METRIC_PARTICLES_05UM, None, aq.particles_05um as f64),
+                (app_metrics::METRIC_PARTICLES_10UM, None, aq.particles_10um as f64),
+                (app_metrics::METRIC_PARTICLES_25UM, None, aq.particles_25um as f64),
+                (app_metrics::METRIC_PARTICLES_50UM, None, aq.particles_50um as f64),
+                (app_metrics::METRIC_PARTICLES_100UM, None, aq.particles_100um as f64),
+                (app_metrics::METRIC_PM10_STANDARD, None, aq.pm10_standard as f64),
+                (app_metrics::METRIC_PM25_STANDARD, None, aq.pm25_standard as f64),
+                (app_metrics::METRIC_PM100_STANDARD, None, aq.pm100_standard as f64),
+                (app_metrics::METRIC_PM10_ENVIRONMENTAL, None, aq.pm10_environmental as f64),
+                (app_metrics::METRIC_PM25_ENVIRONMENTAL, None, aq.pm25_environmental as f64),
+                (app_metrics::METRIC_PM100_ENVIRONMENTAL, None, aq.pm100_environmental as f64),
+            ]
         }
         Variant::PowerMetrics(pwr) => {
             info!("Processing PowerMetrics telemetry for {device_id}");
-            if pwr.ch1_voltage > 0.0 {
-                let mut l_ch1 = labels.clone();
-                l_ch1.push((LABEL_SENSOR_CHANNEL, "ch1".to_string()));
-                gauge!(app_metrics::METRIC_VOLTAGE, &l_ch1).set(pwr.ch1_voltage);
-                gauge!(app_metrics::METRIC_CURRENT, &l_ch1).set(pwr.ch1_current);
-            }
-            if pwr.ch2_voltage > 0.0 {
-                let mut l_ch2 = labels.clone();
-                l_ch2.push((LABEL_SENSOR_CHANNEL, "ch2".to_string()));
-                gauge!(app_metrics::METRIC_VOLTAGE, &l_ch2).set(pwr.ch2_voltage);
-                gauge!(app_metrics::METRIC_CURRENT, &l_ch2).set(pwr.ch2_current);
-            }
-            if pwr.ch3_voltage > 0.0 {
-                let mut l_ch3 = labels.clone();
-                l_ch3.push((LABEL_SENSOR_CHANNEL, "ch3".to_string()));
-                gauge!(app_metrics::METRIC_VOLTAGE, &l_ch3).set(pwr.ch3_voltage);
-                gauge!(app_metrics::METRIC_CURRENT, &l_ch3).set(pwr.ch3_current);
+            let mut readings = vec![];
+            for (channel, voltage, current) in [
+                ("ch1", pwr.ch1_voltage, pwr.ch1_current),
+                ("ch2", pwr.ch2_voltage, pwr.ch2_current),
+                ("ch3", pwr.ch3_voltage, pwr.ch3_current),
+            ] {
+                if voltage > 0.0 {
+                    readings.push((app_metrics::METRIC_VOLTAGE, Some(channel), voltage as f64));
+                    readings.push((app_metrics::METRIC_CURRENT, Some(channel), current as f64));
+                }
             }
+            readings
+        }
+    };
+    let mut node_readings = vec![];
+    for (metric, channel, value) in readings {
+        let mut reading_labels = labels.clone();
+        let mut name = metric.to_string();
+        if let Some(channel) = channel {
+            reading_labels.push((LABEL_SENSOR_CHANNEL, channel.to_string()));
+            name = format!("{metric}_{channel}");
         }
+        gauge!(metric, &reading_labels).set(value);
+        node_readings.push((name, value));
+    }
+    let time = match data.time {
+        0 => crate::get_secs(),
+        t => t as u64
+    };
+    node_db::update_telemetry(&device_id, &node_readings, time).await;
+    store::record_telemetry(&device_id, &node_readings, time);
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use meshtastic::protobufs::{DeviceMetrics, Neighbor};
+
+    const FROM: u32 = 0xa1b2c3d4;
+
+    fn packet(portnum: PortNum, payload: Vec<u8>) -> MeshPacket {
+        MeshPacket {
+            from: FROM,
+            payload_variant: Some(mp_variant::Decoded(Data {
+                portnum: portnum as i32,
+                payload,
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    fn encrypted() -> MeshPacket {
+        MeshPacket {
+            from: FROM,
+            payload_variant: Some(mp_variant::Encrypted(vec![0xb4, 0xf9, 0xd4, 0x32, 0x58])),
+            ..Default::default()
+        }
+    }
+
+    // cutting off the last byte always leaves a field without all of its value
+    fn truncated<M: Message>(message: &M) -> Vec<u8> {
+        let mut bytes = message.encode_to_vec();
+        bytes.pop();
+        bytes
+    }
+
+    fn reason(result: Result<(), ProcessingError>) -> &'static str {
+        result.expect_err("processor should have rejected the payload").reason()
+    }
+
+    #[tokio::test]
+    async fn position_rejects_malformed_payloads() {
+        let position = Position {
+            latitude_i: 377749000,
+            longitude_i: -1224194000,
+            ..Default::default()
+        };
+        assert_eq!(reason(process_position_app(&encrypted()).await), "not_decoded");
+        assert_eq!(reason(process_position_app(&packet(PortNum::PositionApp, truncated(&position))).await), "decode");
+        // every field of a position is optional, so an empty one is just a position without a fix
+        assert!(process_position_app(&packet(PortNum::PositionApp, vec![])).await.is_ok());
+        assert!(process_position_app(&packet(PortNum::PositionApp, position.encode_to_vec())).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn nodeinfo_rejects_malformed_payloads() {
+        let user = User {
+            id: "!a1b2c3d4".to_string(),
+            long_name: "Test node".to_string(),
+            short_name: "TST".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(reason(process_nodeinfo_app(&encrypted()).await), "not_decoded");
+        assert_eq!(reason(process_nodeinfo_app(&packet(PortNum::NodeinfoApp, truncated(&user))).await), "decode");
+        assert!(process_nodeinfo_app(&packet(PortNum::NodeinfoApp, vec![])).await.is_ok());
+        assert!(process_nodeinfo_app(&packet(PortNum::NodeinfoApp, user.encode_to_vec())).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn telemetry_rejects_malformed_payloads() {
+        let telemetry = Telemetry {
+            time: 1700000000,
+            variant: Some(Variant::DeviceMetrics(DeviceMetrics {
+                battery_level: 87,
+                voltage: 4.1,
+                ..Default::default()
+            })),
+        };
+        assert_eq!(reason(process_telemetry_app(&encrypted()).await), "not_decoded");
+        assert_eq!(reason(process_telemetry_app(&packet(PortNum::TelemetryApp, truncated(&telemetry))).await), "decode");
+        assert_eq!(reason(process_telemetry_app(&packet(PortNum::TelemetryApp, vec![])).await), "missing_variant");
+        let no_variant = Telemetry { time: 1700000000, variant: None };
+        assert_eq!(reason(process_telemetry_app(&packet(PortNum::TelemetryApp, no_variant.encode_to_vec())).await), "missing_variant");
+        // a variant from newer firmware (here an empty message in field 15) is skipped by the decoder
+        assert_eq!(reason(process_telemetry_app(&packet(PortNum::TelemetryApp, vec![0x7a, 0x00])).await), "missing_variant");
+        assert!(process_telemetry_app(&packet(PortNum::TelemetryApp, telemetry.encode_to_vec())).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn neighborinfo_rejects_malformed_payloads() {
+        let neighbor_info = NeighborInfo {
+            node_id: FROM,
+            node_broadcast_interval_secs: 900,
+            neighbors: vec![Neighbor {
+                node_id: 0x1234abcd,
+                snr: 6.25,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert_eq!(reason(process_neighborinfo_app(&encrypted()).await), "not_decoded");
+        assert_eq!(reason(process_neighborinfo_app(&packet(PortNum::NeighborinfoApp, truncated(&neighbor_info))).await), "decode");
+        assert!(process_neighborinfo_app(&packet(PortNum::NeighborinfoApp, vec![])).await.is_ok());
+        assert!(process_neighborinfo_app(&packet(PortNum::NeighborinfoApp, neighbor_info.encode_to_vec())).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn routing_rejects_malformed_payloads() {
+        let ack = Routing {
+            variant: Some(routing::Variant::ErrorReason(routing::Error::None as i32)),
+        };
+        let nak = Routing {
+            variant: Some(routing::Variant::ErrorReason(routing::Error::MaxRetransmit as i32)),
+        };
+        assert_eq!(reason(process_routing_app(&encrypted()).await), "not_decoded");
+        assert_eq!(reason(process_routing_app(&packet(PortNum::RoutingApp, truncated(&nak))).await), "decode");
+        assert_eq!(reason(process_routing_app(&packet(PortNum::RoutingApp, vec![])).await), "missing_variant");
+        let no_variant = Routing { variant: None };
+        assert_eq!(reason(process_routing_app(&packet(PortNum::RoutingApp, no_variant.encode_to_vec())).await), "missing_variant");
+        // an error code this build doesn't know about is still counted, as "unknown"
+        let unknown = Routing {
+            variant: Some(routing::Variant::ErrorReason(200)),
+        };
+        assert!(process_routing_app(&packet(PortNum::RoutingApp, unknown.encode_to_vec())).await.is_ok());
+        assert!(process_routing_app(&packet(PortNum::RoutingApp, ack.encode_to_vec())).await.is_ok());
     }
 }
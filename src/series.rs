@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+use metrics::{Counter, Gauge, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit};
+use metrics_util::layers::Layer;
+
+// The prometheus recorder can't delete a series, it only forgets ones that sit idle past
+// METRIC_IDLE_TIMEOUT.  So every gauge and counter handed out is remembered here and touched
+// periodically by keepalive(); retiring a series just stops touching it and lets it age out.
+// A retired gauge is set to NaN first, so it reads as no value rather than a stale one while it does.
+// Histograms can't be touched without recording a sample, so those simply expire when idle.
+
+enum Handle {
+    Counter(Counter),
+    Gauge(Gauge),
+}
+
+lazy_static! {
+    static ref SERIES: Mutex<HashMap<Key, Handle>> = Mutex::new(HashMap::new());
+}
+
+pub struct SeriesTracker<R> {
+    inner: R,
+}
+
+impl<R: Recorder> Recorder for SeriesTracker<R> {
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe_counter(key, unit, description)
+    }
+
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe_gauge(key, unit, description)
+    }
+
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe_histogram(key, unit, description)
+    }
+
+    fn register_counter(&self, key: &Key, metadata: &Metadata<'_>) -> Counter {
+        let counter = self.inner.register_counter(key, metadata);
+        SERIES.lock().unwrap().insert(key.clone(), Handle::Counter(counter.clone()));
+        counter
+    }
+
+    fn register_gauge(&self, key: &Key, metadata: &Metadata<'_>) -> Gauge {
+        let gauge = self.inner.register_gauge(key, metadata);
+        SERIES.lock().unwrap().insert(key.clone(), Handle::Gauge(gauge.clone()));
+        gauge
+    }
+
+    fn register_histogram(&self, key: &Key, metadata: &Metadata<'_>) -> Histogram {
+        self.inner.register_histogram(key, metadata)
+    }
+}
+
+pub struct SeriesLayer;
+
+impl<R> Layer<R> for SeriesLayer {
+    type Output = SeriesTracker<R>;
+
+    fn layer(&self, inner: R) -> Self::Output {
+        SeriesTracker { inner }
+    }
+}
+
+pub fn keepalive() {
+    let series = SERIES.lock().unwrap();
+    for handle in series.values() {
+        match handle {
+            Handle::Counter(c) => c.increment(0),
+            Handle::Gauge(g) => g.increment(0.0),
+        }
+    }
+}
+
+pub fn retire<F: Fn(&Key) -> bool>(predicate: F) -> usize {
+    let mut series = SERIES.lock().unwrap();
+    let before = series.len();
+    series.retain(|key, handle| {
+        if !predicate(key) {
+            return true;
+        }
+        if let Handle::Gauge(g) = handle {
+            g.set(f64::NAN);
+        }
+        false
+    });
+    before - series.len()
+}
+
+pub fn label_value<'a>(key: &'a Key, label: &str) -> Option<&'a str> {
+    key.labels().find(|l| l.key() == label).map(|l| l.value())
+}
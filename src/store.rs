@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use lazy_static::lazy_static;
+use rusqlite::{params, Connection, OpenFlags};
+use serde::Serialize;
+use crate::consts::{HISTORY_COARSE_RESOLUTION, HISTORY_FINE_RESOLUTION, STORE_FLUSH_INTERVAL, STORE_RETENTION_INTERVAL};
+use crate::node_db::{self, Node};
+use crate::structs::StoreConfig;
+use crate::processing;
+
+// Each entry moves the schema up one version; PRAGMA user_version records how many have run.
+// Only ever append to this list.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE nodes (
+        id TEXT PRIMARY KEY,
+        last_heard INTEGER NOT NULL,
+        node TEXT NOT NULL
+    );
+    CREATE INDEX nodes_last_heard ON nodes (last_heard);",
+    "CREATE TABLE telemetry (
+        node_id TEXT NOT NULL,
+        metric TEXT NOT NULL,
+        resolution INTEGER NOT NULL,
+        bucket INTEGER NOT NULL,
+        count INTEGER NOT NULL,
+        value_sum REAL NOT NULL,
+        value_min REAL NOT NULL,
+        value_max REAL NOT NULL,
+        PRIMARY KEY (node_id, metric, resolution, bucket)
+    ) WITHOUT ROWID;",
+];
+
+// Every reading is folded into a bucket at each resolution: the fine one is kept for
+// history_retention, the coarse one as long as the store's retention.
+const RESOLUTIONS: [u64; 2] = [HISTORY_FINE_RESOLUTION, HISTORY_COARSE_RESOLUTION];
+
+enum StoreMessage {
+    Save(Box<Node>),
+    Telemetry(Reading),
+}
+
+struct Writer {
+    tx: Sender<StoreMessage>,
+    handle: JoinHandle<()>,
+}
+
+struct Reading {
+    device_id: String,
+    metric: String,
+    value: f64,
+    time: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct History {
+    pub id: String,
+    pub metric: String,
+    pub resolution: u64,
+    pub points: Vec<HistoryPoint>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryPoint {
+    pub time: u64,
+    pub avg: f64,
+    pub min: f64,
+    pub max: f64,
+    pub count: u64,
+}
+
+lazy_static! {
+    static ref WRITER: Mutex<Option<Writer>> = Mutex::new(None);
+    // nodes that have left the node table but are still in the store, by id
+    static ref DORMANT: Mutex<HashMap<String, Node>> = Mutex::new(HashMap::new());
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("Migrating store to schema version {}", i + 1);
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+// Opens (or creates) the database, puts every stored node heard within node_ttl back into the
+// node table and its gauges, and starts the thread that writes changes from then on.
+pub async fn open(config: &StoreConfig, node_ttl: u64) -> Result<()> {
+    if !config.enabled {
+        return Ok(());
+    }
+    let mut conn = Connection::open(&config.path)?;
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    migrate(&mut conn)?;
+    prune(&conn, config)?;
+
+    // the rest would only be expired again at the first housekeeping, so they start out dormant
+    let cutoff = crate::get_secs().saturating_sub(node_ttl);
+    let (nodes, dormant): (Vec<Node>, Vec<Node>) = load(&conn)?.into_iter()
+        .partition(|node| node_ttl == 0 || node.last_heard.unwrap_or(0) >= cutoff);
+    info!("Restoring {} nodes from {}, {} more haven't been heard within node_ttl", nodes.len(), config.path, dormant.len());
+    for node in nodes.iter() {
+        node_db::restore(node.clone()).await;
+        processing::process_stored_node(node).await;
+    }
+    *DORMANT.lock().unwrap() = dormant.into_iter().map(|node| (node.id.clone(), node)).collect();
+
+    let (tx, rx) = mpsc::channel::<StoreMessage>();
+    let prune_config = config.clone();
+    let handle = std::thread::spawn(move || {
+        let mut pending: HashMap<String, Node> = HashMap::new();
+        let mut readings: Vec<Reading> = vec![];
+        let mut last_flush = Instant::now();
+        let mut last_prune = Instant::now();
+        loop {
+            let disconnected = match rx.recv_timeout(Duration::from_secs(STORE_FLUSH_INTERVAL)) {
+                Ok(StoreMessage::Save(node)) => { pending.insert(node.id.clone(), *node); false }
+                Ok(StoreMessage::Telemetry(reading)) => { readings.push(reading); false }
+                Err(RecvTimeoutError::Timeout) => false,
+                Err(RecvTimeoutError::Disconnected) => true,
+            };
+            // a busy mesh updates the same node many times a second, only the latest is written
+            if disconnected || last_flush.elapsed() >= Duration::from_secs(STORE_FLUSH_INTERVAL) {
+                if let Err(e) = flush(&mut conn, &mut pending, &mut readings) {
+                    error!("Couldn't write to store: {e}");
+                }
+                last_flush = Instant::now();
+            }
+            if last_prune.elapsed() >= Duration::from_secs(STORE_RETENTION_INTERVAL) {
+                if let Err(e) = prune(&conn, &prune_config) {
+                    error!("Couldn't prune store: {e}");
+                }
+                last_prune = Instant::now();
+            }
+            if disconnected {
+                break;
+            }
+        }
+    });
+    *WRITER.lock().unwrap() = Some(Writer { tx, handle });
+    Ok(())
+}
+
+// Nodes expired from the node table after node_ttl stay in the store until retention prunes
+// them, so one that turns up again carries on from what we knew rather than starting blank.
+// They're kept here as they were last saved, which is never behind what the writer has yet to
+// flush, and looking one up doesn't touch the database.
+pub fn set_dormant(node: Node) {
+    if WRITER.lock().unwrap().is_some() {
+        DORMANT.lock().unwrap().insert(node.id.clone(), node);
+    }
+}
+
+pub fn load_node(device_id: &str) -> Option<Node> {
+    DORMANT.lock().unwrap().remove(device_id)
+}
+
+fn load(conn: &Connection) -> Result<Vec<Node>> {
+    let mut statement = conn.prepare("SELECT id, node FROM nodes")?;
+    let rows = statement.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+    let mut nodes = vec![];
+    for row in rows {
+        let (id, json) = row?;
+        match serde_json::from_str(&json) {
+            Ok(node) => nodes.push(node),
+            Err(e) => warn!("Skipping stored node {id}, couldn't read it: {e}"),
+        }
+    }
+    Ok(nodes)
+}
+
+fn flush(conn: &mut Connection, pending: &mut HashMap<String, Node>, readings: &mut Vec<Reading>) -> Result<()> {
+    if pending.is_empty() && readings.is_empty() {
+        return Ok(());
+    }
+    let tx = conn.transaction()?;
+    for (id, node) in pending.drain() {
+        tx.execute(
+            "INSERT INTO nodes (id, last_heard, node) VALUES (?1, ?2, ?3)
+             ON CONFLICT (id) DO UPDATE SET last_heard = excluded.last_heard, node = excluded.node",
+            params![id, node.last_heard.unwrap_or(0) as i64, serde_json::to_string(&node)?],
+        )?;
+    }
+    for reading in readings.drain(..) {
+        for resolution in RESOLUTIONS {
+            let bucket = reading.time - reading.time % resolution;
+            tx.execute(
+                "INSERT INTO telemetry (node_id, metric, resolution, bucket, count, value_sum, value_min, value_max)
+                 VALUES (?1, ?2, ?3, ?4, 1, ?5, ?5, ?5)
+                 ON CONFLICT (node_id, metric, resolution, bucket) DO UPDATE SET
+                     count = count + 1,
+                     value_sum = value_sum + excluded.value_sum,
+                     value_min = min(value_min, excluded.value_min),
+                     value_max = max(value_max, excluded.value_max)",
+                params![reading.device_id, reading.metric, resolution as i64, bucket as i64, reading.value],
+            )?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+fn prune(conn: &Connection, config: &StoreConfig) -> Result<()> {
+    let now = crate::get_secs();
+    if config.history_retention > 0 {
+        let cutoff = now.saturating_sub(config.history_retention) as i64;
+        conn.execute(
+            "DELETE FROM telemetry WHERE resolution = ?1 AND bucket < ?2",
+            params![HISTORY_FINE_RESOLUTION as i64, cutoff],
+        )?;
+    }
+    if config.retention == 0 {
+        return Ok(());
+    }
+    let cutoff = now.saturating_sub(config.retention) as i64;
+    let pruned = conn.execute("DELETE FROM nodes WHERE last_heard < ?1", params![cutoff])?;
+    DORMANT.lock().unwrap().retain(|_, node| node.last_heard.unwrap_or(0) as i64 >= cutoff);
+    if pruned > 0 {
+        info!("Pruned {pruned} nodes not heard from in over {} seconds from the store", config.retention);
+    }
+    conn.execute("DELETE FROM telemetry WHERE bucket < ?1", params![cutoff])?;
+    Ok(())
+}
+
+// Reads go through their own connection, WAL lets them run alongside the writer thread.
+// The fine buckets are used while they still cover `from`, the coarse ones after that.
+pub fn history(config: &StoreConfig, device_id: &str, metric: &str, from: u64, to: u64) -> Result<History> {
+    let resolution = match config.history_retention == 0 || from >= crate::get_secs().saturating_sub(config.history_retention) {
+        true => HISTORY_FINE_RESOLUTION,
+        false => HISTORY_COARSE_RESOLUTION,
+    };
+    let conn = Connection::open_with_flags(&config.path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut statement = conn.prepare(
+        "SELECT bucket, count, value_sum, value_min, value_max FROM telemetry
+         WHERE node_id = ?1 AND metric = ?2 AND resolution = ?3 AND bucket >= ?4 AND bucket <= ?5
+         ORDER BY bucket",
+    )?;
+    let first_bucket = from - from % resolution;
+    let rows = statement.query_map(
+        params![device_id, metric, resolution as i64, first_bucket as i64, to as i64],
+        |row| {
+            let count: i64 = row.get(1)?;
+            let sum: f64 = row.get(2)?;
+            Ok(HistoryPoint {
+                time: row.get::<_, i64>(0)? as u64,
+                avg: sum / count as f64,
+                min: row.get(3)?,
+                max: row.get(4)?,
+                count: count as u64,
+            })
+        },
+    )?;
+    Ok(History {
+        id: device_id.to_string(),
+        metric: metric.to_string(),
+        resolution,
+        points: rows.collect::<rusqlite::Result<Vec<_>>>()?,
+    })
+}
+
+// a no-op when the store is disabled, so nothing is cloned for it
+fn send<F: FnOnce() -> StoreMessage>(message: F) {
+    if let Some(writer) = WRITER.lock().unwrap().as_ref() {
+        let _ = writer.tx.send(message());
+    }
+}
+
+pub fn save(node: &Node) {
+    send(|| StoreMessage::Save(Box::new(node.clone())));
+}
+
+pub fn record_telemetry(device_id: &str, readings: &[(String, f64)], time: u64) {
+    for (metric, value) in readings.iter() {
+        send(|| StoreMessage::Telemetry(Reading {
+            device_id: device_id.to_string(),
+            metric: metric.clone(),
+            value: *value,
+            time,
+        }));
+    }
+}
+
+// writes out anything still pending and waits for the writer to finish
+pub fn close() {
+    let writer = WRITER.lock().unwrap().take();
+    if let Some(Writer { tx, handle, .. }) = writer {
+        drop(tx);
+        if handle.join().is_err() {
+            error!("Store writer thread panicked.");
+        }
+    }
+}
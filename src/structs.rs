@@ -1,6 +1,10 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use meshtastic::protobufs::{FromRadio, ToRadio};
-use serde::Deserialize;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumIter};
+use crate::meshtastic_interaction::parse_node_id;
 
 #[derive(Debug)]
 pub enum IPCMessage {
@@ -8,12 +12,29 @@ pub enum IPCMessage {
     ToRadio(ToRadio),
 }
 
-#[derive(Debug,Clone,Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct AppConfig {
     pub(crate) metrics_port: u16,
     pub(crate) meshtastic_addr: SocketAddr,
+    pub(crate) connection: Option<ConnectionConfig>,
     pub(crate) namespace: String,
-    #[serde(skip_deserializing)]
+    pub(crate) global_labels: HashMap<String, String>,
+    pub(crate) drain_timeout: u64,
+    pub(crate) deadman_timeout: u64,
+    pub(crate) deadman_action: DeadmanAction,
+    pub(crate) heartbeat_interval: u64,
+    pub(crate) channel_buffer_size: usize,
+    pub(crate) reconnect_backoff_initial_ms: u64,
+    pub(crate) reconnect_backoff_max_ms: u64,
+    pub(crate) reconnect_stable_secs: u64,
+    pub(crate) geohash_precision: usize,
+    pub(crate) node_ttl: u64,
+    pub(crate) message_log: MessageLogConfig,
+    pub(crate) traceroute: TracerouteConfig,
+    pub(crate) polling: PollingConfig,
+    pub(crate) store: StoreConfig,
+    #[serde(skip)]
     pub(crate) ___node_id: u32
 }
 
@@ -22,17 +43,256 @@ impl Default for AppConfig {
         AppConfig {
             metrics_port: 9941_u16,
             meshtastic_addr: "127.0.0.1:4403".parse().unwrap(),
+            connection: None,
             namespace: "meshtastic".to_string(),
+            global_labels: HashMap::new(),
+            drain_timeout: 10_u64,
+            deadman_timeout: 300_u64,
+            deadman_action: DeadmanAction::Reconnect,
+            heartbeat_interval: 10_u64,
+            channel_buffer_size: 128_usize,
+            reconnect_backoff_initial_ms: 1_000_u64,
+            reconnect_backoff_max_ms: 300_000_u64,
+            reconnect_stable_secs: 60_u64,
+            geohash_precision: 10_usize,
+            node_ttl: 259_200_u64,
+            message_log: MessageLogConfig::default(),
+            traceroute: TracerouteConfig::default(),
+            polling: PollingConfig::default(),
+            store: StoreConfig::default(),
 
             ___node_id: 0_u32
         }
     }
 }
 
+impl AppConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !(1..=12).contains(&self.geohash_precision) {
+            bail!("geohash_precision must be between 1 and 12.");
+        }
+        if self.deadman_timeout == 0 {
+            bail!("deadman_timeout must be greater than 0.");
+        }
+        if self.heartbeat_interval == 0 || self.heartbeat_interval >= self.deadman_timeout {
+            bail!("heartbeat_interval must be greater than 0 and less than deadman_timeout.");
+        }
+        if self.channel_buffer_size == 0 {
+            bail!("channel_buffer_size must be greater than 0.");
+        }
+        if self.reconnect_backoff_initial_ms == 0 || self.reconnect_backoff_initial_ms > self.reconnect_backoff_max_ms {
+            bail!("reconnect_backoff_initial_ms must be greater than 0 and no more than reconnect_backoff_max_ms.");
+        }
+        if let Some(target) = self.traceroute.targets.iter().find(|t| parse_node_id(t).is_none()) {
+            bail!("traceroute target {target} isn't a device id like !a1b2c3d4.");
+        }
+        if let Some(node) = self.polling.nodes.iter().find(|n| parse_node_id(&n.device_id).is_none()) {
+            bail!("polling device_id {} isn't a device id like !a1b2c3d4.", node.device_id);
+        }
+        Ok(())
+    }
+
+    // `meshtastic_addr` predates the `connection` block, so it's still honored when that's absent
+    pub fn connection(&self) -> Connection {
+        match &self.connection {
+            Some(ConnectionConfig::Tcp { address }) => Connection::TCP(address.ip().to_string(), address.port()),
+            Some(ConnectionConfig::Serial(serial)) => Connection::Serial(serial.clone()),
+            Some(ConnectionConfig::Mqtt(mqtt)) => Connection::Mqtt(mqtt.clone()),
+            None => Connection::TCP(self.meshtastic_addr.ip().to_string(), self.meshtastic_addr.port()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ConnectionConfig {
+    Tcp { address: SocketAddr },
+    Serial(SerialConfig),
+    Mqtt(MqttConfig),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SerialConfig {
+    pub(crate) device: String,
+    #[serde(default)]
+    pub(crate) baud_rate: Option<u32>,
+    #[serde(default)]
+    pub(crate) dtr: Option<bool>,
+    #[serde(default)]
+    pub(crate) rts: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>,
+    pub(crate) client_id: String,
+    pub(crate) topics: Vec<String>,
+    // channel name -> base64 psk, as shown in the meshtastic apps
+    pub(crate) channels: HashMap<String, String>,
+    // how many recent packets to remember when dropping the copies other gateways uplink
+    pub(crate) dedup_window: usize,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            host: "127.0.0.1".to_string(),
+            port: 1883_u16,
+            username: None,
+            password: None,
+            // a broker drops the older of two connections with the same id
+            client_id: format!("meshtastic_exporter-{:08x}", rand::random::<u32>()),
+            topics: vec!["msh/#".to_string()],
+            channels: HashMap::from([("LongFast".to_string(), "AQ==".to_string())]),
+            dedup_window: 1024_usize,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StoreConfig {
+    pub(crate) enabled: bool,
+    pub(crate) path: String,
+    // seconds after which a node we haven't heard from is deleted, 0 to keep them forever.
+    // hourly telemetry history is kept this long too
+    pub(crate) retention: u64,
+    // seconds to keep telemetry history at 5 minute resolution, 0 to keep it as long as the hourly history
+    pub(crate) history_retention: u64,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig {
+            enabled: false,
+            path: "./meshtastic_exporter.db".to_string(),
+            retention: 2_592_000_u64,
+            history_retention: 604_800_u64,
+        }
+    }
+}
+
+// what happens when no packet has arrived within deadman_timeout
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeadmanAction {
+    // drop the connection and let the supervisor make a new one
+    #[default]
+    Reconnect,
+    // shut the exporter down
+    Exit,
+    // keep the connection, only report it as unhealthy
+    Unhealthy,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MessageLogConfig {
+    pub(crate) enabled: bool,
+    pub(crate) path: String,
+    pub(crate) max_bytes: u64,
+    pub(crate) max_files: usize,
+    pub(crate) body: MessageBody,
+}
+
+impl Default for MessageLogConfig {
+    fn default() -> Self {
+        MessageLogConfig {
+            enabled: false,
+            path: "./messages.jsonl".to_string(),
+            max_bytes: 10_485_760_u64,
+            max_files: 5_usize,
+            body: MessageBody::Keep,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageBody {
+    #[default]
+    Keep,
+    Hash,
+    Drop,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TracerouteConfig {
+    pub(crate) enabled: bool,
+    // device ids like "!a1b2c3d4", every known node when empty
+    pub(crate) targets: Vec<String>,
+    pub(crate) interval: u64,
+    pub(crate) min_spacing: u64,
+    pub(crate) timeout: u64,
+    pub(crate) hop_limit: u32,
+}
+
+impl Default for TracerouteConfig {
+    fn default() -> Self {
+        TracerouteConfig {
+            enabled: false,
+            targets: vec![],
+            interval: 3600_u64,
+            min_spacing: 60_u64,
+            timeout: 120_u64,
+            hop_limit: 3_u32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PollingConfig {
+    pub(crate) enabled: bool,
+    pub(crate) nodes: Vec<PollTarget>,
+    // requests per hour, shared by every node
+    pub(crate) budget: u32,
+    pub(crate) timeout: u64,
+    pub(crate) hop_limit: u32,
+}
+
+impl Default for PollingConfig {
+    fn default() -> Self {
+        PollingConfig {
+            enabled: false,
+            nodes: vec![],
+            budget: 30_u32,
+            timeout: 120_u64,
+            hop_limit: 3_u32,
+        }
+    }
+}
+
+// a kind of data is only polled for when its max age is set
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PollTarget {
+    pub(crate) device_id: String,
+    #[serde(default)]
+    pub(crate) telemetry_max_age: Option<u64>,
+    #[serde(default)]
+    pub(crate) position_max_age: Option<u64>,
+}
+
 #[derive(Debug, Clone, Default)]
 pub enum Connection {
     TCP(String, u16),
-    Serial(String),
+    Serial(SerialConfig),
+    Mqtt(MqttConfig),
     #[default]
     None,
 }
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Display, EnumIter)]
+#[strum(serialize_all = "snake_case")]
+pub enum RadioState {
+    #[default]
+    Disconnected,
+    Connecting,
+    Configuring,
+    Connected,
+}
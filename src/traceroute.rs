@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use lazy_static::lazy_static;
+use meshtastic::protobufs::{PortNum, RouteDiscovery};
+use meshtastic::Message;
+use metrics::{counter, gauge};
+use crate::structs::{IPCMessage, RadioState, TracerouteConfig};
+use crate::{app_metrics, consts, lifecycle, series, RADIO_STATE, SETTINGS};
+use crate::meshtastic_interaction::{parse_node_id, request_packet};
+
+struct Probe {
+    target: u32,
+    sent_at: Instant,
+}
+
+#[derive(Default)]
+struct Scheduler {
+    // packet id of the request -> the probe waiting on a reply
+    pending: HashMap<u32, Probe>,
+    last_probed: HashMap<u32, u64>,
+    last_sent: u64,
+}
+
+lazy_static! {
+    static ref SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::default());
+}
+
+// Called every housekeeping tick; hands back at most one request, and only once the
+// spacing since the last one has passed, so probing never hogs the airtime.
+pub async fn next_probe(config: &TracerouteConfig) -> Option<IPCMessage> {
+    if !config.enabled {
+        return None;
+    }
+    expire_probes(config.timeout);
+    if *RADIO_STATE.read().await != RadioState::Connected {
+        return None;
+    }
+    let my_node_id = SETTINGS.read().await.___node_id;
+    let targets: Vec<u32> = match config.targets.is_empty() {
+        true => lifecycle::known_nodes().await.iter().filter_map(|id| parse_node_id(id)).collect(),
+        false => config.targets.iter().filter_map(|id| parse_node_id(id)).collect(),
+    };
+
+    let now = crate::get_secs();
+    let mut scheduler = SCHEDULER.lock().unwrap();
+    if now.saturating_sub(scheduler.last_sent) < config.min_spacing {
+        return None;
+    }
+    let target = targets.into_iter()
+        .filter(|t| *t != my_node_id && *t != consts::BROADCAST_ADDR)
+        .filter(|t| !scheduler.pending.values().any(|p| p.target == *t))
+        .map(|t| (t, scheduler.last_probed.get(&t).copied().unwrap_or(0)))
+        .filter(|(_, last)| now.saturating_sub(*last) >= config.interval)
+        .min_by_key(|(_, last)| *last)
+        .map(|(t, _)| t)?;
+
+    let payload = RouteDiscovery::default().encode_to_vec();
+    let (id, request) = request_packet(target, PortNum::TracerouteApp, payload, config.hop_limit);
+    scheduler.pending.insert(id, Probe { target, sent_at: Instant::now() });
+    scheduler.last_probed.insert(target, now);
+    scheduler.last_sent = now;
+    drop(scheduler);
+
+    let device_id = format!("!{:x}", target);
+    info!("Sending traceroute to {device_id}");
+    let labels = vec![
+        (consts::LABEL_DEVICE_ID, device_id)
+    ];
+    counter!(app_metrics::METRIC_TRACEROUTE_REQUESTS, &labels).increment(1);
+    Some(request)
+}
+
+fn expire_probes(timeout: u64) {
+    let mut scheduler = SCHEDULER.lock().unwrap();
+    let expired: Vec<u32> = scheduler.pending.iter()
+        .filter(|(_, p)| p.sent_at.elapsed().as_secs() >= timeout)
+        .map(|(id, _)| *id)
+        .collect();
+    for id in expired {
+        if let Some(probe) = scheduler.pending.remove(&id) {
+            warn!("Traceroute to !{:x} timed out after {timeout} seconds", probe.target);
+            record_failure(probe.target, "timeout");
+        }
+    }
+}
+
+fn record_failure(target: u32, reason: &str) {
+    let labels = vec![
+        (consts::LABEL_DEVICE_ID, format!("!{:x}", target)),
+        (consts::LABEL_REASON, reason.to_string()),
+    ];
+    counter!(app_metrics::METRIC_TRACEROUTE_FAILURES, &labels).increment(1);
+}
+
+// a routing error naming one of our requests means that traceroute isn't coming back
+pub fn failed(request_id: u32, reason: &str) {
+    let probe = SCHEDULER.lock().unwrap().pending.remove(&request_id);
+    if let Some(probe) = probe {
+        warn!("Traceroute to !{:x} failed: {reason}", probe.target);
+        record_failure(probe.target, reason);
+    }
+}
+
+pub async fn reply(request_id: u32, route: &RouteDiscovery) {
+    let probe = SCHEDULER.lock().unwrap().pending.remove(&request_id);
+    let Some(probe) = probe else {
+        debug!("Traceroute reply for {request_id} isn't one of ours, ignoring.");
+        return;
+    };
+    let my_node_id = SETTINGS.read().await.___node_id;
+    let device_id = format!("!{:x}", probe.target);
+    let labels = vec![
+        (consts::LABEL_DEVICE_ID, device_id.clone())
+    ];
+    info!("Traceroute to {device_id} took {} hops there and {} back", route.route.len(), route.route_back.len());
+    gauge!(app_metrics::METRIC_TRACEROUTE_RTT, &labels).set(probe.sent_at.elapsed().as_secs_f64());
+
+    // snr_towards[i] is what the node at towards[i + 1] heard from the one at towards[i]
+    let mut towards = vec![my_node_id];
+    towards.extend(route.route.iter());
+    towards.push(probe.target);
+    let mut hops = record_path(&labels, consts::DIRECTION_TOWARDS, &towards, &route.route, &route.snr_towards);
+
+    // firmware that predates snr_back doesn't fill in the return path at all
+    if !route.snr_back.is_empty() {
+        let mut back = vec![probe.target];
+        back.extend(route.route_back.iter());
+        back.push(my_node_id);
+        hops.extend(record_path(&labels, consts::DIRECTION_BACK, &back, &route.route_back, &route.snr_back));
+    }
+
+    // the path can change between probes, so hops that aren't on it any more are left to expire
+    series::retire(|key| {
+        key.name() == app_metrics::METRIC_TRACEROUTE_HOP_SNR
+            && series::label_value(key, consts::LABEL_DEVICE_ID) == Some(device_id.as_str())
+            && !hops.iter().any(|(direction, hop, hop_id)| {
+                series::label_value(key, consts::LABEL_DIRECTION) == Some(*direction)
+                    && series::label_value(key, consts::LABEL_HOP) == Some(hop.as_str())
+                    && series::label_value(key, consts::LABEL_HOP_ID) == Some(hop_id.as_str())
+            })
+    });
+}
+
+// returns the (direction, hop, hop_id) of every hop series it set
+fn record_path<'a>(labels: &[(&'static str, String)], direction: &'a str, nodes: &[u32], route: &[u32], snr: &[i32]) -> Vec<(&'a str, String, String)> {
+    let mut recorded = Vec::new();
+    let mut direction_labels = labels.to_vec();
+    direction_labels.push((consts::LABEL_DIRECTION, direction.to_string()));
+    gauge!(app_metrics::METRIC_TRACEROUTE_ROUTE_LENGTH, &direction_labels).set(route.len() as f64);
+    for (hop, value) in snr.iter().enumerate() {
+        if *value == consts::TRACEROUTE_SNR_UNKNOWN {
+            continue;
+        }
+        let Some(hop_id) = nodes.get(hop + 1) else { break };
+        let mut hop_labels = direction_labels.clone();
+        hop_labels.push((consts::LABEL_HOP, hop.to_string()));
+        hop_labels.push((consts::LABEL_HOP_ID, format!("!{:x}", hop_id)));
+        gauge!(app_metrics::METRIC_TRACEROUTE_HOP_SNR, &hop_labels).set(*value as f64 * consts::TRACEROUTE_SNR_FACTOR);
+        recorded.push((direction, hop.to_string(), format!("!{:x}", hop_id)));
+    }
+    recorded
+}